@@ -0,0 +1,122 @@
+use crate::filetype::{closing_bracket, FileType};
+use crate::row::Row;
+
+type Pos = (usize, usize);
+
+pub enum BracketMatch {
+    // The bracket at the cursor and its partner
+    Matched(Pos, Pos),
+    // The bracket at the cursor has no partner, or the partner is a different kind of bracket
+    Unmatched(Pos),
+}
+
+fn is_bracket(c: char) -> bool {
+    matches!(c, '(' | ')' | '[' | ']' | '{' | '}')
+}
+
+fn starts_with_at(chars: &[char], i: usize, s: &str) -> bool {
+    s.chars().enumerate().all(|(n, c)| chars.get(i + n) == Some(&c))
+}
+
+// Length of the character literal such as 'a' or '\n' starting at i. A single quote not closed
+// soon is not a literal, e.g. a lifetime `'a` in Rust.
+fn char_literal_len(chars: &[char], i: usize) -> Option<usize> {
+    match chars.get(i + 1)? {
+        '\\' => chars[i + 2..].iter().position(|&c| c == '\'').map(|p| p + 3),
+        _ if chars.get(i + 2) == Some(&'\'') => Some(3),
+        _ => None,
+    }
+}
+
+// Brackets in code of the file type. Brackets in strings and comments are skipped.
+fn code_brackets(rows: &[Row], filetype: FileType) -> Vec<(Pos, char)> {
+    let quotes = filetype.quotes();
+    let line_comment = filetype.line_comment();
+    let block_comment = filetype.block_comment();
+    let mut brackets = vec![];
+    // Closing delimiter of the block comment being scanned. It can continue to following lines.
+    let mut in_block: Option<&str> = None;
+
+    for (y, row) in rows.iter().enumerate() {
+        let chars: Vec<_> = row.buffer().chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            if let Some(end) = in_block {
+                if starts_with_at(&chars, i, end) {
+                    in_block = None;
+                    i += end.chars().count();
+                } else {
+                    i += 1;
+                }
+                continue;
+            }
+
+            let c = chars[i];
+            // Checked first since a block comment can start with a line comment, e.g. `--[[` in Lua
+            if let Some((start, end)) = block_comment {
+                if starts_with_at(&chars, i, start) {
+                    in_block = Some(end);
+                    i += start.chars().count();
+                    continue;
+                }
+            }
+            if line_comment.map(|s| starts_with_at(&chars, i, s)).unwrap_or(false) {
+                break;
+            }
+            if c == '\'' && filetype.char_literals() {
+                i += char_literal_len(&chars, i).unwrap_or(1);
+                continue;
+            }
+            if quotes.contains(&c) {
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                i += 1;
+                continue;
+            }
+            if is_bracket(c) {
+                brackets.push(((i, y), c));
+            }
+            i += 1;
+        }
+    }
+
+    brackets
+}
+
+// Finds the partner of the bracket at the cursor, or of the bracket just before the cursor as
+// after typing a closing bracket
+pub fn match_at(rows: &[Row], filetype: FileType, (x, y): Pos) -> Option<BracketMatch> {
+    let row = rows.get(y)?;
+    let at = [Some(x), x.checked_sub(1)]
+        .into_iter()
+        .flatten()
+        .find(|&x| x < row.len() && is_bracket(row.char_at(x)))?;
+
+    let brackets = code_brackets(rows, filetype);
+    let target = brackets.iter().position(|&(pos, _)| pos == (at, y))?;
+
+    // Openers waiting for their closers as indices of `brackets`
+    let mut stack: Vec<usize> = vec![];
+    for (i, &(_, c)) in brackets.iter().enumerate() {
+        if closing_bracket(c).is_some() {
+            stack.push(i);
+            continue;
+        }
+        match stack.last() {
+            Some(&open) if closing_bracket(brackets[open].1) == Some(c) => {
+                stack.pop();
+                if open == target || i == target {
+                    let partner = if open == target { i } else { open };
+                    return Some(BracketMatch::Matched((at, y), brackets[partner].0));
+                }
+            }
+            // A closer which doesn't close the innermost opener is mismatched
+            _ if i == target => return Some(BracketMatch::Unmatched((at, y))),
+            _ => {}
+        }
+    }
+
+    Some(BracketMatch::Unmatched((at, y)))
+}
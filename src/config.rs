@@ -0,0 +1,341 @@
+use crate::error::{Error, Result};
+use crate::filetype::FileType;
+use crate::gutter::LineNumbers;
+use crate::keymap::{KeyBinding, KeyContext, KeyProfile};
+use crate::row::TAB_STOP;
+use crate::term_color::Theme;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// auto_pairs = false
+//
+// [auto_pairs]
+// default = true   # File types not listed
+// markdown = false
+#[derive(Clone, PartialEq)]
+pub struct AutoPairs {
+    pub default: bool,
+    pub filetypes: Vec<(FileType, bool)>,
+}
+
+impl Default for AutoPairs {
+    fn default() -> Self {
+        Self {
+            default: true,
+            filetypes: vec![],
+        }
+    }
+}
+
+impl AutoPairs {
+    pub fn enabled(&self, filetype: FileType) -> bool {
+        self.filetypes
+            .iter()
+            .find(|(ft, _)| *ft == filetype)
+            .map(|&(_, enabled)| enabled)
+            .unwrap_or(self.default)
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub tab_width: usize,
+    pub expand_tab: bool,
+    pub theme: Theme,
+    pub undo_limit: usize,
+    // `None` keeps messages until they are replaced
+    pub message_timeout: Option<Duration>,
+    pub line_numbers: LineNumbers,
+    pub wrap: bool,
+    // Lines kept between the cursor and the top and bottom edges of windows
+    pub scroll_off: usize,
+    // Wrap frames in synchronized output mode so that terminals show them at once. Terminals
+    // which do not support it ignore the mode.
+    pub sync_output: bool,
+    // Copy indentation to new lines and indent after opening brackets depending on file type
+    pub auto_indent: bool,
+    // Insert closing brackets and quotes with opening ones
+    pub auto_pairs: AutoPairs,
+    pub key_profile: KeyProfile,
+    // Overrides of the default key bindings in `[keys.<context>]` tables
+    pub key_bindings: Vec<KeyBinding>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tab_width: TAB_STOP,
+            expand_tab: false,
+            theme: Theme::Dark,
+            undo_limit: 1000,
+            message_timeout: None,
+            line_numbers: LineNumbers::Off,
+            wrap: false,
+            scroll_off: 0,
+            sync_output: false,
+            auto_indent: true,
+            auto_pairs: AutoPairs::default(),
+            key_profile: KeyProfile::Default,
+            key_bindings: vec![],
+        }
+    }
+}
+
+fn expect_int(key: &str, value: &toml::Value, min: i64) -> std::result::Result<usize, String> {
+    match value.as_integer() {
+        Some(i) if i >= min => Ok(i as usize),
+        _ => Err(format!("`{}` must be an integer equal to or greater than {}", key, min)),
+    }
+}
+
+fn expect_bool(key: &str, value: &toml::Value) -> std::result::Result<bool, String> {
+    value
+        .as_bool()
+        .ok_or_else(|| format!("`{}` must be true or false", key))
+}
+
+fn expect_str<'a>(key: &str, value: &'a toml::Value) -> std::result::Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or_else(|| format!("`{}` must be a string", key))
+}
+
+fn expect_table<'a>(key: &str, value: &'a toml::Value) -> std::result::Result<&'a toml::value::Table, String> {
+    value
+        .as_table()
+        .ok_or_else(|| format!("`{}` must be a table", key))
+}
+
+fn parse_auto_pairs(value: &toml::Value) -> std::result::Result<AutoPairs, String> {
+    if let Some(enabled) = value.as_bool() {
+        return Ok(AutoPairs {
+            default: enabled,
+            filetypes: vec![],
+        });
+    }
+    let table = value
+        .as_table()
+        .ok_or_else(|| "`auto_pairs` must be true, false or a table of file types".to_string())?;
+    let mut auto_pairs = AutoPairs::default();
+    for (name, enabled) in table.iter() {
+        let enabled = expect_bool(&format!("auto_pairs.{}", name), enabled)?;
+        if name == "default" {
+            auto_pairs.default = enabled;
+            continue;
+        }
+        let filetype = FileType::from_name(name).ok_or_else(|| format!("Unknown file type `auto_pairs.{}`", name))?;
+        auto_pairs.filetypes.push((filetype, enabled));
+    }
+    Ok(auto_pairs)
+}
+
+// [keys.editor]
+// "C-s" = "save"
+// "M-x" = ""      # Empty string removes the default binding
+fn parse_key_bindings(value: &toml::Value) -> std::result::Result<Vec<KeyBinding>, String> {
+    let mut bindings = vec![];
+    for (name, table) in expect_table("keys", value)?.iter() {
+        let context = KeyContext::from_name(name)
+            .ok_or_else(|| format!("Unknown key binding context `keys.{}`", name))?;
+        let key = format!("keys.{}", name);
+        for (keys, command) in expect_table(&key, table)?.iter() {
+            let command = match expect_str(&format!("{}.{:?}", key, keys), command)? {
+                "" => None,
+                c => Some(c.to_string()),
+            };
+            bindings.push(KeyBinding {
+                context,
+                keys: keys.clone(),
+                command,
+            });
+        }
+    }
+    Ok(bindings)
+}
+
+impl Config {
+    // $XDG_CONFIG_HOME/berry/config.toml, falling back to ~/.config/berry/config.toml
+    pub fn path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("berry").join("config.toml"))
+    }
+
+    // Loads the user configuration. A missing file is not an error and gives the defaults.
+    pub fn load() -> Result<Self> {
+        let path = match Self::path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(Self::default()),
+        };
+        let source = fs::read_to_string(&path)?;
+        Self::parse(&source).map_err(|msg| Error::InvalidConfig(path, msg))
+    }
+
+    pub fn parse(source: &str) -> std::result::Result<Self, String> {
+        let table = match source.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => table,
+            Ok(_) => return Err("Top level must be a table".to_string()),
+            Err(err) => return Err(err.to_string()),
+        };
+
+        let mut config = Self::default();
+        for (key, value) in table.iter() {
+            config.set(key, value)?;
+        }
+
+        Ok(config)
+    }
+
+    // Sets one option, e.g. from a `:set` command. The value is validated as in the config file.
+    pub fn set(&mut self, key: &str, value: &toml::Value) -> std::result::Result<(), String> {
+        match key {
+            "tab_width" => self.tab_width = expect_int(key, value, 1)?,
+            "expand_tab" => self.expand_tab = expect_bool(key, value)?,
+            "theme" => {
+                self.theme = match expect_str(key, value)? {
+                    "dark" => Theme::Dark,
+                    "light" => Theme::Light,
+                    "mono" => Theme::Mono,
+                    t => return Err(format!("Unknown theme {:?}. Use \"dark\", \"light\" or \"mono\"", t)),
+                };
+            }
+            "undo_limit" => self.undo_limit = expect_int(key, value, 1)?,
+            "message_timeout" => {
+                let secs = expect_int(key, value, 0)?;
+                self.message_timeout = if secs == 0 {
+                    None
+                } else {
+                    Some(Duration::from_secs(secs as u64))
+                };
+            }
+            "line_numbers" => {
+                self.line_numbers = match expect_str(key, value)? {
+                    "off" => LineNumbers::Off,
+                    "absolute" => LineNumbers::Absolute,
+                    "relative" => LineNumbers::Relative,
+                    "hybrid" => LineNumbers::Hybrid,
+                    n => {
+                        return Err(format!(
+                            "Unknown line_numbers {:?}. Use \"off\", \"absolute\", \"relative\" or \"hybrid\"",
+                            n
+                        ))
+                    }
+                };
+            }
+            "wrap" => self.wrap = expect_bool(key, value)?,
+            "scroll_off" => self.scroll_off = expect_int(key, value, 0)?,
+            "sync_output" => self.sync_output = expect_bool(key, value)?,
+            "auto_indent" => self.auto_indent = expect_bool(key, value)?,
+            "auto_pairs" => self.auto_pairs = parse_auto_pairs(value)?,
+            "key_profile" => {
+                let name = expect_str(key, value)?;
+                self.key_profile = KeyProfile::from_name(name).ok_or_else(|| {
+                    format!("Unknown key_profile {:?}. Use \"default\", \"emacs\" or \"vi\"", name)
+                })?;
+            }
+            "keys" => self.key_bindings = parse_key_bindings(value)?,
+            _ => return Err(format!("Unknown key `{}`", key)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_err(source: &str) -> String {
+        match Config::parse(source) {
+            Ok(_) => panic!("{:?} was accepted", source),
+            Err(msg) => msg,
+        }
+    }
+
+    #[test]
+    fn parse_empty_source_gives_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.tab_width, TAB_STOP);
+        assert!(!config.expand_tab);
+        assert_eq!(config.theme, Theme::Dark);
+        assert_eq!(config.message_timeout, None);
+        assert_eq!(config.line_numbers, LineNumbers::Off);
+    }
+
+    #[test]
+    fn parse_options() {
+        let source = r#"
+            tab_width = 2
+            expand_tab = true
+            theme = "mono"
+            undo_limit = 10
+            message_timeout = 3
+            line_numbers = "hybrid"
+            wrap = true
+        "#;
+        let config = Config::parse(source).unwrap();
+        assert_eq!(config.tab_width, 2);
+        assert!(config.expand_tab);
+        assert_eq!(config.theme, Theme::Mono);
+        assert_eq!(config.undo_limit, 10);
+        assert_eq!(config.message_timeout, Some(Duration::from_secs(3)));
+        assert_eq!(config.line_numbers, LineNumbers::Hybrid);
+        assert!(config.wrap);
+    }
+
+    #[test]
+    fn parse_zero_message_timeout_keeps_messages() {
+        let config = Config::parse("message_timeout = 0").unwrap();
+        assert_eq!(config.message_timeout, None);
+    }
+
+    #[test]
+    fn parse_rejects_invalid_values() {
+        assert_eq!(parse_err("tab_width = 0"), "`tab_width` must be an integer equal to or greater than 1");
+        assert_eq!(parse_err("expand_tab = 1"), "`expand_tab` must be true or false");
+        assert_eq!(parse_err("theme = 1"), "`theme` must be a string");
+        assert!(parse_err("theme = \"blue\"").starts_with("Unknown theme \"blue\""));
+        assert!(parse_err("line_numbers = \"on\"").starts_with("Unknown line_numbers \"on\""));
+        assert_eq!(parse_err("tabwidth = 4"), "Unknown key `tabwidth`");
+        assert!(!parse_err("tab_width = ").is_empty());
+    }
+
+    #[test]
+    fn parse_key_bindings_per_context() {
+        let source = r#"
+            [keys.editor]
+            "C-s" = "quit"
+            "M-x" = ""
+            [keys.prompt]
+            "C-j" = "accept"
+        "#;
+        let config = Config::parse(source).unwrap();
+        let bindings: Vec<_> = config
+            .key_bindings
+            .iter()
+            .map(|b| (b.context, b.keys.as_str(), b.command.as_deref()))
+            .collect();
+        assert_eq!(
+            bindings,
+            vec![
+                (KeyContext::Editor, "C-s", Some("quit")),
+                (KeyContext::Editor, "M-x", None),
+                (KeyContext::Prompt, "C-j", Some("accept")),
+            ],
+        );
+        assert_eq!(parse_err("keys = 1"), "`keys` must be a table");
+        assert_eq!(parse_err("[keys.insert]"), "Unknown key binding context `keys.insert`");
+        assert_eq!(parse_err("[keys.editor]\n\"C-s\" = 1"), "`keys.editor.\"C-s\"` must be a string");
+    }
+
+    #[test]
+    fn parse_key_profile() {
+        assert_eq!(Config::parse("").unwrap().key_profile, KeyProfile::Default);
+        assert_eq!(Config::parse("key_profile = \"emacs\"").unwrap().key_profile, KeyProfile::Emacs);
+        assert!(parse_err("key_profile = \"nano\"").starts_with("Unknown key_profile \"nano\""));
+    }
+}
@@ -18,7 +18,15 @@ pub enum EditDiff {
 }
 
 impl EditDiff {
-    pub fn apply(&self, rows: &mut Vec<Row>, which: UndoRedo) -> (usize, usize) {
+    // Where the cursor goes after the line at `y` is removed. The first line has no previous line.
+    fn end_of_previous_line(rows: &[Row], y: usize) -> (usize, usize) {
+        match y.checked_sub(1) {
+            Some(y) => (rows[y].len(), y),
+            None => (0, 0),
+        }
+    }
+
+    pub fn apply(&self, rows: &mut Vec<Row>, which: UndoRedo, tab_stop: usize) -> (usize, usize) {
         use UndoRedo::*;
         match *self {
             EditDiff::InsertChar(x, y, c) => match which {
@@ -69,7 +77,7 @@ impl EditDiff {
             }
             EditDiff::Newline => match which {
                 Redo => {
-                    rows.push(Row::empty());
+                    rows.push(Row::empty(tab_stop));
                     (0, rows.len() - 1)
                 }
                 Undo => {
@@ -80,12 +88,12 @@ impl EditDiff {
             }
             EditDiff::InsertLine(y, ref s) => match which {
                 Redo => {
-                    rows.insert(y, Row::new(s).unwrap());
+                    rows.insert(y, Row::new(s, tab_stop).unwrap());
                     (0, y)
                 }
                 Undo => {
                     rows.remove(y);
-                    (rows[y - 1].len(), y - 1)
+                    Self::end_of_previous_line(rows, y)
                 }
             }
             EditDiff::DeleteLine(y, ref s) => match which {
@@ -95,13 +103,13 @@ impl EditDiff {
                     } else {
                         rows.remove(y);
                     }
-                    (rows[y - 1].len(), y - 1)
+                    Self::end_of_previous_line(rows, y)
                 }
                 Undo => {
                     if y == rows.len() {
-                        rows.push(Row::new(s).unwrap());
+                        rows.push(Row::new(s, tab_stop).unwrap());
                     } else {
-                        rows.insert(y, Row::new(s).unwrap());
+                        rows.insert(y, Row::new(s, tab_stop).unwrap());
                     }
                     (0, y)
                 }
@@ -1,11 +1,25 @@
+use crate::brackets::BracketMatch;
+use crate::config::Config;
 use crate::error::Result;
+use crate::ex::{self, ExCommand, LineRange};
+use crate::filter;
 use crate::input::{InputSeq, KeySeq};
+use crate::keymap::{self, Command, KeyContext, KeyProfile, Keymap, Lookup};
+use crate::macros::{self, MacroInput};
 use crate::prompt::{self, Prompt, PromptResult};
-use crate::screen::Screen;
+use crate::screen::{RecenterPos, Screen};
 use crate::status_bar::StatusBar;
 use crate::text_buffer::{CursorDir, TextBuffer};
+use crate::vi::{self, Feed, Mode, Vi};
+use crate::window::SplitDir;
+use std::cmp;
 use std::io::Write;
+use std::mem;
 use std::path::Path;
+use std::time::{Duration, Instant};
+
+// Keys which can follow a pending key sequence are listed after this delay
+const WHICH_KEY_DELAY: Duration = Duration::from_millis(500);
 
 enum EditStep {
     Continue(InputSeq),
@@ -22,12 +36,26 @@ impl EditStep {
 }
 
 pub struct Editor<I: Iterator<Item = Result<InputSeq>>, W: Write> {
-    input: I,
+    input: MacroInput<I>,
     quitting: bool,
     screen: Screen<W>,
     bufs: Vec<TextBuffer>,
     buf_idx: usize,
-    status_bar: StatusBar,
+    config: Config,
+    keymap: Keymap,
+    // Keys typed so far of a key sequence such as C-x C-s
+    pending_keys: Vec<InputSeq>,
+    // When the keys which can follow the pending keys should be listed
+    which_key_at: Option<Instant>,
+    // Text deleted by the last kill command, shared by all buffers
+    killed: String,
+    last_command: Option<Command>,
+    recenter_pos: RecenterPos,
+    // State of modal editing when the vi key profile is used
+    vi: Option<Vi>,
+    // Keys of the last recorded or loaded keyboard macro
+    last_macro: Option<Vec<InputSeq>>,
+    playing_macro: bool,
 }
 
 impl<I, W> Editor<I, W>
@@ -41,15 +69,24 @@ where
         output: W,
         window_size: Option<(u16, u16)>,
     ) -> Result<Editor<I, W>> {
-        let screen = Screen::new(window_size, output)?;
         let status_bar = StatusBar::from_buffer(&buf, (1, 1));
+        let screen = Screen::new(window_size, output, status_bar)?;
         Ok(Editor {
-            input,
+            input: MacroInput::new(input),
             quitting: false,
             screen,
             bufs: vec![buf],
             buf_idx: 0,
-            status_bar,
+            config: Config::default(),
+            keymap: Keymap::new(KeyProfile::Default),
+            pending_keys: vec![],
+            which_key_at: None,
+            killed: String::new(),
+            last_command: None,
+            recenter_pos: RecenterPos::Middle,
+            vi: None,
+            last_macro: None,
+            playing_macro: false,
         })
     }
     
@@ -66,16 +103,25 @@ where
         if paths.is_empty() {
             return Self::new(input, output, window_size);
         }
-        let screen = Screen::new(window_size, output)?;
         let bufs: Vec<_> = paths.iter().map(TextBuffer::open).collect::<Result<_>>()?;
         let status_bar = StatusBar::from_buffer(&bufs[0], (1, bufs.len()));
+        let screen = Screen::new(window_size, output, status_bar)?;
         Ok(Editor {
-            input,
+            input: MacroInput::new(input),
             quitting: false,
             screen,
             bufs,
             buf_idx: 0,
-            status_bar,
+            config: Config::default(),
+            keymap: Keymap::new(KeyProfile::Default),
+            pending_keys: vec![],
+            which_key_at: None,
+            killed: String::new(),
+            last_command: None,
+            recenter_pos: RecenterPos::Middle,
+            vi: None,
+            last_macro: None,
+            playing_macro: false,
         })
     }
 
@@ -87,16 +133,77 @@ where
         &mut self.bufs[self.buf_idx]
     }
 
-    fn refresh_status_bar(&mut self) {
-        self.status_bar.set_buf_pos((self.buf_idx + 1, self.bufs.len()));
-        self.status_bar.update_from_buf(&self.bufs[self.buf_idx]);
+    // Problems in key bindings don't prevent starting the editor. They are shown in the message bar.
+    pub fn set_config(&mut self, config: Config) {
+        self.screen.apply_config(&config);
+        for buf in self.bufs.iter_mut() {
+            buf.update_config(&self.config, &config);
+        }
+        let (keymap, problems) = Keymap::with_bindings(config.key_profile, &config.key_bindings);
+        if !problems.is_empty() {
+            self.screen.set_error_message(format!("Invalid key bindings: {}", problems.join(", ")));
+        }
+        self.keymap = keymap;
+        match (config.key_profile, &self.vi) {
+            (KeyProfile::Vi, Some(_)) => {}
+            (KeyProfile::Vi, None) => {
+                self.vi = Some(Vi::default());
+                self.screen.set_mode(Some(Mode::Normal));
+            }
+            _ => {
+                self.vi = None;
+                self.screen.set_mode(None);
+            }
+        }
+        self.config = config;
+    }
+
+    fn reload_config(&mut self) {
+        match Config::load() {
+            Ok(config) => {
+                self.screen.set_info_message("Reloaded configuration");
+                self.set_config(config);
+            }
+            Err(err) => self.screen.set_error_message(err.to_string()),
+        }
     }
 
     fn render_screen(&mut self) -> Result<()> {
-        self.refresh_status_bar();
-        self.screen.render(&self.bufs[self.buf_idx], &self.status_bar)?;
-        self.status_bar.redraw = false;
-        Ok(())
+        self.screen.render(&self.bufs)
+    }
+
+    fn switch_window(&mut self, focused: Option<(usize, (usize, usize))>) -> bool {
+        match focused {
+            Some((buf_idx, (x, y))) => {
+                self.buf_mut().clear_multi_cursors();
+                self.buf_idx = buf_idx;
+                self.buf_mut().restore_cursor(x, y);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn split_window(&mut self, dir: SplitDir) {
+        let cursor = self.buf().cursor();
+        if !self.screen.split_window(dir, cursor) {
+            self.screen.set_error_message("Not enough room to split the window");
+        }
+    }
+
+    fn close_window(&mut self) {
+        let focused = self.screen.close_window();
+        if !self.switch_window(focused) {
+            self.screen.set_info_message("Cannot close the last window");
+        }
+    }
+
+    fn cycle_window(&mut self, forward: bool) {
+        let cursor = self.buf().cursor();
+        let focused = self.screen.cycle_window(forward, cursor);
+        if !self.switch_window(focused) {
+            self.screen.set_info_message("There is no other window");
+        }
     }
 
     fn handle_quit(&mut self, s: InputSeq) -> EditStep {
@@ -105,66 +212,689 @@ where
             EditStep::Quit
         } else {
             self.quitting = true;
-            self.screen.set_error_message(
-                "At least one file has unsaved changes! Press ^Q again to quit or ^S to save",
+            // Show the keys actually bound since the default keys may be remapped
+            let key = |cmd: Command| {
+                self.keymap
+                    .key_for(KeyContext::Editor, cmd)
+                    .unwrap_or_else(|| format!("`{}`", cmd))
+            };
+            let msg = format!(
+                "At least one file has unsaved changes! Press {} again to quit or {} to save",
+                key(Command::Quit),
+                key(Command::Save),
             );
+            self.screen.set_error_message(msg);
             EditStep::Continue(s)
         }
     }
 
-    fn process_keypress(&mut self, s: InputSeq) -> Result<EditStep> {
-        use KeySeq::*;
+    fn execute(&mut self, cmd: Command) -> Result<()> {
+        use Command::*;
 
-        let prev_cursor = self.buf().cursor();
-        
-        match &s {
-            InputSeq {
-                key: Unidentified, ..
-            } => return Ok(EditStep::Continue(s)),
-            InputSeq { key, ctrl: true, ..
-            } => match key {
-                Key(b'd') => self.buf_mut().delete_right_char(),
-                Key(b'h') => self.buf_mut().delete_char(),
-                Key(b's') => self.save()?,
-                Key(b'm') => {
-                    self.buf_mut().insert_line()
+        let vertical = matches!(
+            cmd,
+            CursorUp | CursorDown | PageDown | PageUp | HalfPageDown | HalfPageUp | ScrollViewDown | ScrollViewUp
+        );
+        if !vertical {
+            self.buf_mut().clear_goal_col();
+        }
+
+        match cmd {
+            Save => self.save()?,
+            // Handled by `process_keypress` since it may stop the editor
+            Quit => {}
+            Undo => {
+                if !self.buf_mut().undo() {
+                    self.screen.set_info_message("No older change");
                 }
-                Key(b'u') => {
-                    if !self.buf_mut().undo() {
-                        self.screen.set_info_message("No older change");
-                    }
+            }
+            Redo => {
+                if !self.buf_mut().redo() {
+                    self.screen.set_info_message("Buffer is already newest");
                 }
-                Key(b'r') => {
-                    if !self.buf_mut().redo() {
-                        self.screen.set_info_message("Buffer is already newest");
+            }
+            InsertLine => self.buf_mut().insert_line(),
+            DeleteChar => self.buf_mut().delete_char(),
+            DeleteRightChar => self.buf_mut().delete_right_char(),
+            CursorUp => self.move_cursor_vertically(CursorDir::Up),
+            CursorDown => self.move_cursor_vertically(CursorDir::Down),
+            CursorLeft => self.buf_mut().move_cursor_one(CursorDir::Left),
+            CursorRight => self.buf_mut().move_cursor_one(CursorDir::Right),
+            LineStart => self.buf_mut().move_to_line_start(),
+            LineEnd => self.buf_mut().move_to_line_end(),
+            ForwardWord => self.buf_mut().move_word_forward(),
+            BackwardWord => self.buf_mut().move_word_backward(),
+            KillLine | KillWord | BackwardKillWord => {
+                let killed = match cmd {
+                    KillLine => self.buf_mut().kill_line(),
+                    KillWord => self.buf_mut().kill_word(),
+                    _ => self.buf_mut().kill_word_backward(),
+                };
+                // Consecutive kills are accumulated so that they can be yanked at once. Text killed
+                // backward goes before the text killed so far.
+                if matches!(self.last_command, Some(KillLine | KillWord | BackwardKillWord)) {
+                    if cmd == BackwardKillWord {
+                        self.killed.insert_str(0, &killed);
+                    } else {
+                        self.killed.push_str(&killed);
                     }
+                } else {
+                    self.killed = killed;
                 }
-                Key(b'q') => return Ok(self.handle_quit(s)),
-                _ => {}
             }
-            InputSeq { key, .. } => match key {
-                Key(0x08) => self.buf_mut().delete_char(),
-                Key(0x7f) => self.buf_mut().delete_char(),
-                Key(b'\r') => self.buf_mut().insert_line(),
-                Key(b) if !b.is_ascii_control() => self.buf_mut().insert_char(*b as char),
-                Utf8Key(c) => self.buf_mut().insert_char(*c),
-                UpKey => self.buf_mut().move_cursor_one(CursorDir::Up),
-                LeftKey => self.buf_mut().move_cursor_one(CursorDir::Left),
-                DownKey => self.buf_mut().move_cursor_one(CursorDir::Down),
-                RightKey => self.buf_mut().move_cursor_one(CursorDir::Right),
-                _ => {}
+            DuplicateLines => self.buf_mut().duplicate_lines(),
+            DeleteLines => self.buf_mut().delete_lines(),
+            MoveLinesUp | MoveLinesDown => {
+                let dir = if cmd == MoveLinesUp { CursorDir::Up } else { CursorDir::Down };
+                if !self.buf_mut().move_lines(dir) {
+                    self.screen.set_info_message("Cannot move lines further");
+                }
+            }
+            JoinLines => {
+                if !self.buf_mut().join_lines() {
+                    self.screen.set_info_message("No line to join");
+                }
             }
+            OpenLineBelow => self.buf_mut().open_line(CursorDir::Down),
+            OpenLineAbove => self.buf_mut().open_line(CursorDir::Up),
+            PageDown | PageUp | HalfPageDown | HalfPageUp => {
+                let rows = self.screen.text_rows();
+                // A couple of lines of a page are left on the screen for context
+                let lines = match cmd {
+                    PageDown | PageUp => cmp::max(rows.saturating_sub(2), 1),
+                    _ => cmp::max(rows / 2, 1),
+                };
+                let down = matches!(cmd, PageDown | HalfPageDown);
+                let delta = if down { lines as isize } else { -(lines as isize) };
+                self.screen.scroll_view(&self.bufs[self.buf_idx], delta);
+                let dir = if down { CursorDir::Down } else { CursorDir::Up };
+                for _ in 0..lines {
+                    self.move_cursor_vertically(dir);
+                }
+            }
+            ScrollViewDown | ScrollViewUp => {
+                let delta = if cmd == ScrollViewDown { 1 } else { -1 };
+                let moves = self.screen.scroll_view(&self.bufs[self.buf_idx], delta);
+                let dir = if moves > 0 { CursorDir::Down } else { CursorDir::Up };
+                for _ in 0..moves.unsigned_abs() {
+                    self.move_cursor_vertically(dir);
+                }
+            }
+            Recenter => {
+                self.recenter_pos = if self.last_command == Some(Recenter) {
+                    self.recenter_pos.next()
+                } else {
+                    RecenterPos::Middle
+                };
+                self.screen.recenter(&self.bufs[self.buf_idx], self.recenter_pos);
+            }
+            Yank => {
+                let text = self.killed.clone();
+                self.buf_mut().yank(&text);
+            }
+            ToggleMark => self.buf_mut().toggle_mark(),
+            ClearCursors => {
+                self.buf_mut().clear_multi_cursors();
+            }
+            Indent => self.buf_mut().indent(),
+            Dedent => self.buf_mut().dedent(),
+            AddCursorAtNextMatch => {
+                if let Err(msg) = self.buf_mut().add_cursor_at_next_match() {
+                    self.screen.set_info_message(msg);
+                }
+            }
+            AddCursorAbove => {
+                self.buf_mut().add_cursor_vertically(CursorDir::Up);
+            }
+            AddCursorBelow => {
+                self.buf_mut().add_cursor_vertically(CursorDir::Down);
+            }
+            SplitSelectionIntoLines => {
+                if !self.buf_mut().split_selection_into_lines() {
+                    self.screen.set_info_message("No selection to split");
+                }
+            }
+            SplitHorizontal => self.split_window(SplitDir::Horizontal),
+            SplitVertical => self.split_window(SplitDir::Vertical),
+            CloseWindow => self.close_window(),
+            NextWindow => self.cycle_window(true),
+            PreviousWindow => self.cycle_window(false),
+            GrowWindow => {
+                if !self.screen.resize_window(1) {
+                    self.screen.set_info_message("Cannot grow the window");
+                }
+            }
+            ShrinkWindow => {
+                if !self.screen.resize_window(-1) {
+                    self.screen.set_info_message("Cannot shrink the window");
+                }
+            }
+            ToggleWrap => {
+                let msg = if self.buf_mut().toggle_wrap() {
+                    "Soft-wrap enabled"
+                } else {
+                    "Soft-wrap disabled"
+                };
+                self.screen.set_info_message(msg);
+            }
+            ToggleExpandTab => {
+                let msg = if self.buf_mut().toggle_expand_tab() {
+                    "Indent with spaces"
+                } else {
+                    "Indent with tabs"
+                };
+                self.screen.set_info_message(msg);
+            }
+            CycleLineNumbers => {
+                let mode = self.screen.cycle_line_numbers();
+                self.screen.set_info_message(format!("Line numbers: {}", mode));
+            }
+            ReloadConfig => self.reload_config(),
+            StartMacro => self.start_macro(),
+            // Handled by `process_keypress` since the keys of the command must not be recorded
+            StopMacro => {}
+            // Handled by `process_keypress` since it may stop the editor
+            OpenCommandLine => {}
+            FilterThroughShell => {
+                let template = "Filter through: {} (^G or ESC to cancel)";
+                if let PromptResult::Input(command) = self.prompt::<prompt::NoAction>(template, true)? {
+                    self.filter_lines(None, &command);
+                }
+            }
+            JumpToMatchingBracket => match self.buf().matching_bracket() {
+                Some(BracketMatch::Matched(_, (x, y))) => self.buf_mut().jump_to(x, y),
+                Some(BracketMatch::Unmatched(_)) => self.screen.set_error_message("No matching bracket"),
+                None => self.screen.set_error_message("No bracket at cursor"),
+            },
+            ToggleComment => {
+                if let Err(msg) = self.buf_mut().toggle_comment() {
+                    self.screen.set_error_message(msg);
+                }
+            }
+            PlayMacro => self.play_macro(1)?,
+            RepeatMacro => self.repeat_macro()?,
+            SaveMacro => self.save_macro()?,
+            LoadMacro => self.load_macro()?,
+            // Only meaningful while typing a key sequence, which is handled by `process_keypress`
+            Cancel => {}
+            // Commands only available in prompt
+            Accept | DeleteWordBackward | Complete => {}
         }
 
+        Ok(())
+    }
+
+    fn show_which_key(&mut self) {
+        match self.which_key_at {
+            Some(at) if Instant::now() >= at => {}
+            _ => return,
+        }
+        self.which_key_at = None;
+        let entries: Vec<_> = self
+            .keymap
+            .continuations(KeyContext::Editor, &self.pending_keys)
+            .into_iter()
+            .map(|(key, cmd)| {
+                let desc = match cmd {
+                    Some(cmd) => cmd.name().to_string(),
+                    None => "+prefix".to_string(),
+                };
+                (key, desc)
+            })
+            .collect();
+        self.screen.set_hints(&entries);
+    }
+
+    fn finish_key_sequence(&mut self) -> Vec<InputSeq> {
+        self.which_key_at = None;
+        self.screen.unset_hints();
+        mem::take(&mut self.pending_keys)
+    }
+
+    fn process_keypress(&mut self, s: InputSeq) -> Result<EditStep> {
+        use KeySeq::*;
+
+        if s.key == Unidentified {
+            self.show_which_key();
+            return Ok(EditStep::Continue(s));
+        }
+
+        if self.pending_keys.is_empty() {
+            if let Some(step) = self.process_vi_keypress(&s)? {
+                return Ok(step);
+            }
+        }
+
+        let prev_cursor = self.buf().cursor();
+
+        if !self.pending_keys.is_empty() && self.keymap.is_bound_to(KeyContext::Editor, &s, Command::Cancel) {
+            self.finish_key_sequence();
+            self.screen.set_info_message("Canceled");
+            return Ok(EditStep::Continue(s));
+        }
+
+        self.pending_keys.push(s.clone());
+        let cmd = match self.keymap.lookup(KeyContext::Editor, &self.pending_keys) {
+            Lookup::Prefix => {
+                // Shown as "C-x-" like Emacs
+                self.screen.set_info_message(format!("{}-", keymap::keys_name(&self.pending_keys)));
+                self.which_key_at = Some(Instant::now() + WHICH_KEY_DELAY);
+                return Ok(EditStep::Continue(s));
+            }
+            Lookup::Command(cmd) => Some(cmd),
+            Lookup::Unbound => None,
+        };
+
+        let keys = self.finish_key_sequence();
+        if keys.len() > 1 {
+            self.screen.unset_message();
+        }
+
+        match cmd {
+            Some(Command::Quit) => return Ok(self.handle_quit(s)),
+            Some(Command::StopMacro) => self.stop_macro(keys.len()),
+            Some(Command::OpenCommandLine) => {
+                if self.command_line()? {
+                    return Ok(EditStep::Quit);
+                }
+            }
+            Some(cmd) => self.execute(cmd)?,
+            None if keys.len() > 1 => {
+                self.screen.set_error_message(format!("{} is undefined", keymap::keys_name(&keys)));
+            }
+            None => match &s {
+                InputSeq { ctrl: true, .. } | InputSeq { alt: true, .. } => {}
+                InputSeq { key: Key(b), .. } if !b.is_ascii_control() => {
+                    self.buf_mut().insert_char(*b as char)
+                }
+                InputSeq { key: Utf8Key(c), .. } => self.buf_mut().insert_char(*c),
+                _ => {}
+            },
+        }
+
+        self.last_command = cmd;
+        self.finish_keypress(prev_cursor);
+        Ok(EditStep::Continue(s))
+    }
+
+    fn finish_keypress(&mut self, prev_cursor: (usize, usize)) {
         if let Some(line) = self.buf_mut().finish_edit() {
-            self.screen.set_dirty_start(line);
+            self.screen.set_dirty_start(self.buf_idx, line);
         }
         if self.buf().cursor() != prev_cursor {
             self.screen.cursor_moved = true;
         }
-        
         self.quitting = false;
-        Ok(EditStep::Continue(s))
+    }
+
+    // Keys in normal and visual modes are handled by the vi state. `None` means the key should
+    // be handled with the keymap as in insert mode.
+    fn process_vi_keypress(&mut self, s: &InputSeq) -> Result<Option<EditStep>> {
+        let vi = match &mut self.vi {
+            Some(vi) => vi,
+            None => return Ok(None),
+        };
+        let buf = &mut self.bufs[self.buf_idx];
+        let prev_cursor = buf.cursor();
+
+        if vi.mode == Mode::Insert {
+            if *s != InputSeq::new(KeySeq::Key(0x1b)) {
+                vi.record_insert(s.clone());
+                return Ok(None);
+            }
+            vi.leave_insert(buf);
+        } else {
+            let cmd = match vi.feed(s.clone()) {
+                Feed::NotVi => return Ok(None),
+                Feed::Pending | Feed::Invalid => return Ok(Some(EditStep::Continue(s.clone()))),
+                Feed::Command(cmd) => cmd,
+            };
+            // Motions of vi do not keep goal columns
+            buf.clear_goal_col();
+            if cmd.action == vi::Action::Repeat {
+                self.repeat_vi_change(cmd.count)?;
+            } else if cmd.action == vi::Action::CommandLine {
+                if self.command_line()? {
+                    return Ok(Some(EditStep::Quit));
+                }
+            } else if let Err(msg) = vi.execute(buf, cmd) {
+                self.screen.set_info_message(msg);
+            }
+        }
+
+        let mode = self.vi.as_ref().map(|vi| vi.mode);
+        self.screen.set_mode(mode);
+        self.finish_keypress(prev_cursor);
+        Ok(Some(EditStep::Continue(s.clone())))
+    }
+
+    // `.` runs the last change again and replays keys typed in insert mode after it
+    fn repeat_vi_change(&mut self, count: Option<usize>) -> Result<()> {
+        let (cmd, keys) = match self.vi.as_ref().and_then(|vi| vi.repeat(count)) {
+            Some(change) => change,
+            None => return Ok(()),
+        };
+        let vi = self.vi.as_mut().unwrap();
+        if let Err(msg) = vi.execute(&mut self.bufs[self.buf_idx], cmd) {
+            self.screen.set_info_message(msg);
+        }
+        if vi.mode == Mode::Insert {
+            for seq in keys {
+                self.process_keypress(seq)?;
+            }
+            self.process_keypress(InputSeq::new(KeySeq::Key(0x1b)))?;
+        }
+        Ok(())
+    }
+
+    fn start_macro(&mut self) {
+        if self.input.is_recording() {
+            self.screen.set_info_message("Already recording a macro");
+        } else if self.playing_macro {
+            self.screen.set_error_message("Cannot record a macro while playing a macro");
+        } else {
+            self.input.start_recording();
+            self.screen.set_recording(true);
+            self.screen.set_info_message("Recording macro...");
+        }
+    }
+
+    fn stop_macro(&mut self, trailing: usize) {
+        self.screen.set_recording(false);
+        match self.input.stop_recording(trailing) {
+            Some(keys) if keys.is_empty() => self.screen.set_info_message("Macro is empty"),
+            Some(keys) => {
+                self.screen.set_info_message(format!("Recorded macro of {} keys", keys.len()));
+                self.last_macro = Some(keys);
+            }
+            None => self.screen.set_info_message("Not recording a macro"),
+        }
+    }
+
+    // Recorded keys are fed back through the input so that prompts opened by the macro also
+    // receive them. All edits by the playback are undone at once.
+    fn play_macro(&mut self, count: usize) -> Result<()> {
+        if self.input.is_recording() {
+            self.screen.set_error_message("Cannot play a macro while recording a macro");
+            return Ok(());
+        }
+        if self.playing_macro {
+            self.screen.set_error_message("Cannot play a macro recursively");
+            return Ok(());
+        }
+        let keys = match &self.last_macro {
+            Some(keys) => keys.clone(),
+            None => {
+                self.screen.set_info_message("No macro is recorded");
+                return Ok(());
+            }
+        };
+
+        for _ in 0..count {
+            self.input.play(&keys);
+        }
+        self.playing_macro = true;
+
+        // Edits in each buffer the macro switches to are undone at once. Buffers are never removed
+        // so their indices stay valid.
+        let mut grouped = vec![];
+        let mut result = Ok(());
+        while self.input.is_playing() {
+            let seq = match self.input.next() {
+                Some(Ok(seq)) => seq,
+                _ => break,
+            };
+            if !grouped.contains(&self.buf_idx) {
+                self.bufs[self.buf_idx].begin_undo_group();
+                grouped.push(self.buf_idx);
+            }
+            match self.process_keypress(seq) {
+                Ok(EditStep::Continue(_)) => {}
+                // Quitting the editor is not replayed
+                Ok(EditStep::Quit) => {
+                    self.input.cancel_playback();
+                    self.screen.set_info_message("Macro stopped at quit");
+                }
+                Err(err) => {
+                    self.input.cancel_playback();
+                    result = Err(err);
+                }
+            }
+        }
+
+        self.playing_macro = false;
+        for idx in grouped {
+            self.bufs[idx].end_undo_group();
+        }
+        result
+    }
+
+    fn repeat_macro(&mut self) -> Result<()> {
+        let template = "Repeat macro how many times: {} (^G or ESC to cancel)";
+        if let PromptResult::Input(input) = self.prompt::<prompt::NoAction>(template, true)? {
+            match input.trim().parse::<usize>() {
+                Ok(count) if count > 0 => self.play_macro(count)?,
+                _ => self.screen.set_error_message(format!("Invalid repeat count {:?}", input)),
+            }
+        }
+        Ok(())
+    }
+
+    fn save_macro(&mut self) -> Result<()> {
+        let keys = match &self.last_macro {
+            Some(keys) => keys.clone(),
+            None => {
+                self.screen.set_info_message("No macro is recorded");
+                return Ok(());
+            }
+        };
+        let template = "Save macro as: {} (^G or ESC to cancel)";
+        if let PromptResult::Input(name) = self.prompt::<prompt::NoAction>(template, true)? {
+            match macros::save(&name, &keys) {
+                Ok(path) => {
+                    let msg = format!("Saved macro {:?} to {}", name, path.display());
+                    self.screen.set_info_message(msg);
+                }
+                Err(err) => self.screen.set_error_message(err.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    fn load_macro(&mut self) -> Result<()> {
+        let template = "Load macro: {} (^G or ESC to cancel)";
+        if let PromptResult::Input(name) = self.prompt::<prompt::NoAction>(template, true)? {
+            match macros::load(&name) {
+                Ok(Some(keys)) => {
+                    let key = self
+                        .keymap
+                        .key_for(KeyContext::Editor, Command::PlayMacro)
+                        .unwrap_or_else(|| format!("`{}`", Command::PlayMacro));
+                    let msg = format!("Loaded macro {:?}. Press {} to play it", name, key);
+                    self.screen.set_info_message(msg);
+                    self.last_macro = Some(keys);
+                }
+                Ok(None) => self.screen.set_error_message(format!("No macro named {:?}", name)),
+                Err(err) => self.screen.set_error_message(err.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    // Runs a command typed in the `:` prompt. Returns true when the editor should quit.
+    fn command_line(&mut self) -> Result<bool> {
+        let input = match self.prompt::<ex::CompleteCommand>(":{}", true)? {
+            PromptResult::Input(input) => input,
+            PromptResult::Canceled => return Ok(false),
+        };
+        match ex::parse(&input) {
+            Ok(cmd) => self.run_ex_command(cmd),
+            Err(msg) => {
+                self.screen.set_error_message(msg);
+                Ok(false)
+            }
+        }
+    }
+
+    fn run_ex_command(&mut self, cmd: ExCommand) -> Result<bool> {
+        match cmd {
+            ExCommand::Write(None) => self.save()?,
+            ExCommand::Write(Some(path)) => match self.buf().write_copy(path) {
+                Ok(msg) => self.screen.set_info_message(msg),
+                Err(msg) => self.screen.set_error_message(msg),
+            },
+            ExCommand::SaveAs(path) => {
+                match self.buf_mut().save_as(path) {
+                    Ok(msg) => self.screen.set_info_message(msg),
+                    Err(msg) => self.screen.set_error_message(msg),
+                }
+            }
+            ExCommand::WriteQuit => {
+                self.save()?;
+                return Ok(!self.buf().modified() && self.can_quit(false));
+            }
+            ExCommand::Quit { force } => return Ok(self.can_quit(force)),
+            ExCommand::Edit(path) => self.edit_file(&path),
+            ExCommand::Buffer(name) => self.select_buffer(&name),
+            ExCommand::Goto(addr) => {
+                let len = self.buf().rows().len();
+                match LineRange(addr, addr).resolve(self.buf().cy(), len) {
+                    Ok((y, _)) => self.buf_mut().jump_to(0, y),
+                    Err(msg) => self.screen.set_error_message(msg),
+                }
+            }
+            ExCommand::Set(name, value) => {
+                let mut config = self.config.clone();
+                match config.set(&name, &value) {
+                    Ok(()) => {
+                        self.screen.set_info_message(format!("{} = {}", name, value));
+                        self.set_config(config);
+                    }
+                    Err(msg) => self.screen.set_error_message(msg),
+                }
+            }
+            ExCommand::Substitute { range, pattern, replacement, global } => {
+                let len = self.buf().rows().len();
+                let (sy, ey) = match range.resolve(self.buf().cy(), len) {
+                    Ok(lines) => lines,
+                    Err(msg) => {
+                        self.screen.set_error_message(msg);
+                        return Ok(false);
+                    }
+                };
+                match self.buf_mut().substitute(sy, ey, &pattern, &replacement, global) {
+                    (0, _) => self.screen.set_error_message(format!("Pattern not found: {}", pattern)),
+                    (matches, lines) => {
+                        let msg = format!("{} substitutions on {} lines", matches, lines);
+                        self.screen.set_info_message(msg);
+                    }
+                }
+            }
+            ExCommand::Filter { range: Some(range), command } => {
+                let len = self.buf().rows().len();
+                match range.resolve(self.buf().cy(), len) {
+                    Ok(lines) => self.filter_lines(Some(lines), &command),
+                    Err(msg) => self.screen.set_error_message(msg),
+                }
+            }
+            ExCommand::Filter { range: None, command } => self.filter_lines(None, &command),
+        }
+        Ok(false)
+    }
+
+    // Replaces the lines with the output of the shell command taking the lines as stdin. Without
+    // lines, the selected lines or the whole buffer are filtered. On failure the buffer is kept.
+    fn filter_lines(&mut self, lines: Option<(usize, usize)>, command: &str) {
+        let buf = self.buf();
+        let len = buf.rows().len();
+        let (sy, ey) = lines.or_else(|| buf.selected_lines()).unwrap_or((0, len));
+        // The cursor can be at the end of buffer, after the last line
+        let rows = &buf.rows()[cmp::min(sy, len)..cmp::min(ey + 1, len)];
+        let mut input = String::new();
+        for row in rows {
+            input.push_str(row.buffer());
+            input.push('\n');
+        }
+        let num_lines = rows.len();
+
+        match filter::run(command, &input) {
+            Ok(output) if num_lines == 0 => {
+                self.buf_mut().jump_to(0, len);
+                self.buf_mut().insert_str(output.strip_suffix('\n').unwrap_or(&output));
+            }
+            Ok(output) => {
+                self.buf_mut().replace_lines(sy, sy + num_lines - 1, &output);
+                let msg = format!("Filtered {} lines through {:?}", num_lines, command);
+                self.screen.set_info_message(msg);
+            }
+            Err(msg) => self.screen.set_error_message(msg),
+        }
+    }
+
+    // `:q` refuses to quit while a buffer has unsaved changes unless it is forced by `:q!`
+    fn can_quit(&mut self, force: bool) -> bool {
+        if force {
+            return true;
+        }
+        match self.bufs.iter().find(|b| b.modified()) {
+            Some(buf) => {
+                let msg = format!("No write since last change of {} (add ! to override)", buf.filename());
+                self.screen.set_error_message(msg);
+                false
+            }
+            None => true,
+        }
+    }
+
+    fn switch_buffer(&mut self, buf_idx: usize) {
+        if buf_idx != self.buf_idx {
+            self.buf_mut().clear_multi_cursors();
+            self.buf_idx = buf_idx;
+            self.screen.show_buffer(buf_idx);
+        }
+    }
+
+    // Opens the file in a new buffer, or switches to the buffer if it is already open
+    fn edit_file(&mut self, path: &str) {
+        if let Some(idx) = self.bufs.iter().position(|b| b.has_file() && b.filename() == path) {
+            self.switch_buffer(idx);
+            return;
+        }
+        match TextBuffer::open(path) {
+            Ok(mut buf) => {
+                buf.apply_config(&self.config);
+                self.bufs.push(buf);
+                self.switch_buffer(self.bufs.len() - 1);
+            }
+            Err(err) => self.screen.set_error_message(format!("Could not open {}: {}", path, err)),
+        }
+    }
+
+    // Buffers are selected by the index shown in the status bar or by a part of the file name
+    fn select_buffer(&mut self, name: &str) {
+        if let Ok(n) = name.parse::<usize>() {
+            if 1 <= n && n <= self.bufs.len() {
+                self.switch_buffer(n - 1);
+            } else {
+                self.screen.set_error_message(format!("No buffer {}", n));
+            }
+            return;
+        }
+        let found: Vec<_> = (0..self.bufs.len()).filter(|&i| self.bufs[i].filename().contains(name)).collect();
+        match found.as_slice() {
+            [idx] => self.switch_buffer(*idx),
+            [] => self.screen.set_error_message(format!("No buffer matches {:?}", name)),
+            _ => self.screen.set_error_message(format!("More than one buffer matches {:?}", name)),
+        }
+    }
+
+    fn move_cursor_vertically(&mut self, dir: CursorDir) {
+        if self.buf().wrap() {
+            let width = self.screen.text_cols();
+            self.buf_mut().move_cursor_visual(dir, width);
+        } else {
+            self.buf_mut().move_cursor_one(dir);
+        }
     }
 
     fn save(&mut self) -> Result<()> {
@@ -195,12 +925,7 @@ where
         prompt: &str,
         empty_is_cancel: bool,
     ) -> Result<PromptResult> {
-        Prompt::new(
-            &mut self.screen,
-            &mut self.bufs[self.buf_idx],
-            &mut self.status_bar,
-            empty_is_cancel,
-        )
+        Prompt::new(&mut self.screen, &mut self.bufs, &self.keymap, empty_is_cancel)
         .run::<A, _, _>(prompt, &mut self.input)
     }
     
@@ -211,8 +936,14 @@ where
             return Ok(EditStep::Quit);
         };
 
+        // Input wakes up when the terminal is resized. The screen is rendered again below
+        self.screen.maybe_resize()?;
+
+        let idle = seq.key == KeySeq::Unidentified;
         let step = self.process_keypress(seq)?;
-        if step.continues() {
+        if step.continues() && idle {
+            self.screen.render_idle(&self.bufs)?;
+        } else if step.continues() {
             self.render_screen()?;
         }
         
@@ -222,8 +953,7 @@ where
     
     pub fn first_paint(&mut self) -> Result<Edit<'_, I, W>> {
         if self.buf().is_scratch() {
-            self.screen.render_welcome(&self.status_bar)?;
-            self.status_bar.redraw = false;
+            self.screen.render_welcome(&self.bufs)?;
         } else {
             self.render_screen()?;
         }
@@ -1,13 +1,16 @@
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
-    TooSmallWindow(u16, u16),
     UnknownWindowSize,
     NotUtf8Input(Vec<u8>),
     ControlCharInText(char),
+    InvalidConfig(PathBuf, String),
+    InvalidMacroFile(PathBuf, String),
 }
 
 impl fmt::Display for Error {
@@ -15,11 +18,6 @@ impl fmt::Display for Error {
         use Error::*;
         match self {
             IoError(err) => write!(f, "{}", err),
-            TooSmallWindow(w, h) => write!(
-                f,
-                "Screen {}x{} is too small. At least 1x3 is necessary in width x height",
-                w, h
-            ),
             UnknownWindowSize => write!(f, "Could not detect terminal window size"),
             NotUtf8Input(seq) => {
                 write!(f, "Cannot handle non-UTF8 multi-byte input sequence: ")?;
@@ -29,6 +27,12 @@ impl fmt::Display for Error {
                 Ok(())
             }
             ControlCharInText(c) => write!(f, "Invalid character for text is included: {:?}", c),
+            InvalidConfig(path, msg) => {
+                write!(f, "Invalid config file {}: {}", path.display(), msg)
+            }
+            InvalidMacroFile(path, msg) => {
+                write!(f, "Invalid macro file {}: {}", path.display(), msg)
+            }
         }
     }
 }
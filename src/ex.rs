@@ -0,0 +1,457 @@
+// Command language of the `:` command line such as `:w`, `:e path`, `:42` or `:%s/foo/bar/g`
+use crate::prompt::{self, Prompt};
+use std::io::Write;
+
+// Names of commands with the length of their shortest abbreviation, e.g. `w` for `write`
+const COMMANDS: &[(&str, usize)] = &[
+    ("buffer", 1),
+    ("edit", 1),
+    ("goto", 2),
+    ("quit", 1),
+    ("saveas", 3),
+    ("set", 2),
+    ("substitute", 1),
+    ("wq", 2),
+    ("write", 1),
+    ("xit", 1),
+];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Address {
+    // 1-based line number as typed
+    Line(usize),
+    Current,
+    Last,
+}
+
+impl Address {
+    // Resolves the address to a 0-based line in a buffer of `len` lines
+    fn resolve(self, cy: usize, len: usize) -> Result<usize, String> {
+        match self {
+            Address::Line(0) => Ok(0),
+            // Line 1 exists even in an empty buffer
+            Address::Line(n) if n <= len.max(1) => Ok(n - 1),
+            Address::Line(n) => Err(format!("Line {} is out of range (1..{})", n, len)),
+            Address::Current => Ok(cy),
+            Address::Last => Ok(len.saturating_sub(1)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LineRange(pub Address, pub Address);
+
+impl LineRange {
+    const CURRENT: LineRange = LineRange(Address::Current, Address::Current);
+
+    // Resolves the range to 0-based inclusive lines
+    pub fn resolve(self, cy: usize, len: usize) -> Result<(usize, usize), String> {
+        let start = self.0.resolve(cy, len)?;
+        let end = self.1.resolve(cy, len)?;
+        if start > end {
+            return Err("Backwards range given".to_string());
+        }
+        Ok((start, end))
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ExCommand {
+    // `:w` saves the buffer and `:w path` writes a copy of it to the path
+    Write(Option<String>),
+    SaveAs(String),
+    WriteQuit,
+    Quit { force: bool },
+    Edit(String),
+    // Index (1-based) or part of the file name
+    Buffer(String),
+    Goto(Address),
+    // `:set wrap` sets `true`, `:set nowrap` sets `false` and `:set tab_width=4` sets the value
+    Set(String, toml::Value),
+    Substitute {
+        range: LineRange,
+        pattern: String,
+        replacement: String,
+        global: bool,
+    },
+    // `:[range]!command`. Without a range, the selected lines or the whole buffer are filtered.
+    Filter {
+        range: Option<LineRange>,
+        command: String,
+    },
+}
+
+fn parse_address(s: &str) -> (Option<Address>, &str) {
+    if let Some(rest) = s.strip_prefix('.') {
+        return (Some(Address::Current), rest);
+    }
+    if let Some(rest) = s.strip_prefix('$') {
+        return (Some(Address::Last), rest);
+    }
+    let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    match s[..digits].parse() {
+        Ok(n) => (Some(Address::Line(n)), &s[digits..]),
+        Err(_) => (None, s),
+    }
+}
+
+fn parse_range(s: &str) -> Result<(Option<LineRange>, &str), String> {
+    if let Some(rest) = s.strip_prefix('%') {
+        return Ok((Some(LineRange(Address::Line(1), Address::Last)), rest));
+    }
+    let (start, rest) = match parse_address(s) {
+        (Some(addr), rest) => (addr, rest),
+        (None, rest) => return Ok((None, rest)),
+    };
+    let rest = rest.trim_start();
+    let rest = match rest.strip_prefix(',') {
+        Some(rest) => rest.trim_start(),
+        None => return Ok((Some(LineRange(start, start)), rest)),
+    };
+    match parse_address(rest) {
+        (Some(end), rest) => Ok((Some(LineRange(start, end)), rest)),
+        (None, _) => Err("Line number is expected after ','".to_string()),
+    }
+}
+
+// Resolves an abbreviated command name such as `w` or `sav`
+fn command_name(name: &str) -> Option<&'static str> {
+    COMMANDS
+        .iter()
+        .find(|(full, min)| name.len() >= *min && full.starts_with(name))
+        .map(|(full, _)| *full)
+}
+
+// Splits `/pattern/replacement/flags`. The first character is the delimiter and it can be
+// escaped with a backslash in the pattern and the replacement.
+fn parse_substitute(args: &str) -> Result<(String, String, bool), String> {
+    let mut chars = args.chars();
+    let delim = match chars.next() {
+        Some(c) if !c.is_alphanumeric() && !c.is_whitespace() && c != '\\' => c,
+        _ => return Err("Usage: :[range]s/pattern/replacement/[g]".to_string()),
+    };
+
+    let mut parts = vec![String::new()];
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(n) if n == delim => parts.last_mut().unwrap().push(n),
+                Some(n) => {
+                    let part = parts.last_mut().unwrap();
+                    part.push('\\');
+                    part.push(n);
+                }
+                None => parts.last_mut().unwrap().push('\\'),
+            },
+            c if c == delim && parts.len() < 3 => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+
+    let mut parts = parts.into_iter();
+    let pattern = parts.next().unwrap_or_default();
+    let replacement = parts.next().unwrap_or_default();
+    let flags = parts.next().unwrap_or_default();
+    if pattern.is_empty() {
+        return Err("Empty pattern".to_string());
+    }
+    let global = match flags.trim() {
+        "" => false,
+        "g" => true,
+        f => return Err(format!("Unknown flags {:?}", f)),
+    };
+    Ok((pattern, replacement, global))
+}
+
+// Values which are not valid TOML such as `dark` in `:set theme=dark` are taken as strings
+fn parse_set(args: &str) -> Result<(String, toml::Value), String> {
+    if args.is_empty() {
+        return Err("Usage: :set option[=value]".to_string());
+    }
+    let (name, value) = match args.split_once('=') {
+        Some((name, value)) => {
+            let value = value.trim();
+            let value = match format!("v = {}", value).parse::<toml::Value>() {
+                Ok(toml::Value::Table(mut t)) => t.remove("v").unwrap(),
+                _ => toml::Value::String(value.to_string()),
+            };
+            (name.trim(), value)
+        }
+        None => match args.strip_prefix("no") {
+            Some(name) => (name, toml::Value::Boolean(false)),
+            None => (args, toml::Value::Boolean(true)),
+        },
+    };
+    Ok((name.to_string(), value))
+}
+
+fn required<'a>(name: &str, args: &'a str) -> Result<&'a str, String> {
+    if args.is_empty() {
+        Err(format!(":{} needs an argument", name))
+    } else {
+        Ok(args)
+    }
+}
+
+pub fn parse(line: &str) -> Result<ExCommand, String> {
+    let line = line.trim();
+    let line = line.strip_prefix(':').unwrap_or(line).trim_start();
+    let (range, rest) = parse_range(line)?;
+    let rest = rest.trim_start();
+
+    // Only a line number jumps to the line
+    if rest.is_empty() {
+        return match range {
+            Some(LineRange(_, end)) => Ok(ExCommand::Goto(end)),
+            None => Err("No command given".to_string()),
+        };
+    }
+
+    if let Some(command) = rest.strip_prefix('!') {
+        let command = command.trim();
+        if command.is_empty() {
+            return Err("Usage: :[range]!command".to_string());
+        }
+        return Ok(ExCommand::Filter {
+            range,
+            command: command.to_string(),
+        });
+    }
+
+    let name_len = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_alphabetic()).len();
+    let name = &rest[..name_len];
+    let mut args = &rest[name_len..];
+    let force = match args.strip_prefix('!') {
+        Some(a) => {
+            args = a;
+            true
+        }
+        None => false,
+    };
+    let full = match command_name(name) {
+        Some(full) => full,
+        None if name.is_empty() => return Err(format!("Not a command: {}", rest)),
+        None => return Err(format!("Unknown command :{}", name)),
+    };
+
+    if full == "substitute" {
+        let (pattern, replacement, global) = parse_substitute(args.trim_start())?;
+        return Ok(ExCommand::Substitute {
+            range: range.unwrap_or(LineRange::CURRENT),
+            pattern,
+            replacement,
+            global,
+        });
+    }
+
+    if range.is_some() {
+        return Err(format!(":{} does not take a range", full));
+    }
+    if force && full != "quit" {
+        return Err(format!(":{} does not take '!'", full));
+    }
+    if !args.is_empty() && !args.starts_with(char::is_whitespace) {
+        return Err(format!("Unknown command :{}", rest));
+    }
+    let args = args.trim();
+
+    let cmd = match full {
+        "write" if args.is_empty() => ExCommand::Write(None),
+        "write" => ExCommand::Write(Some(args.to_string())),
+        "saveas" => ExCommand::SaveAs(required(full, args)?.to_string()),
+        "wq" | "xit" => ExCommand::WriteQuit,
+        "quit" => ExCommand::Quit { force },
+        "edit" => ExCommand::Edit(required(full, args)?.to_string()),
+        "buffer" => ExCommand::Buffer(required(full, args)?.to_string()),
+        "goto" => match parse_address(required(full, args)?) {
+            (Some(addr), "") => ExCommand::Goto(addr),
+            _ => return Err(format!("Invalid line number {:?}", args)),
+        },
+        "set" => {
+            let (name, value) = parse_set(args)?;
+            ExCommand::Set(name, value)
+        }
+        _ => unreachable!(),
+    };
+
+    if !args.is_empty() && matches!(cmd, ExCommand::WriteQuit | ExCommand::Quit { .. }) {
+        return Err(format!(":{} does not take an argument", full));
+    }
+    Ok(cmd)
+}
+
+// Completes the command name being typed to the longest common prefix of the candidates. A unique
+// candidate is followed by a space. `None` means nothing can be completed.
+pub fn complete(input: &str) -> Option<String> {
+    let trimmed = input.trim_start();
+    let name_start = trimmed.len() - trimmed.trim_start_matches(|c: char| !c.is_ascii_alphabetic()).len();
+    let (prefix, name) = trimmed.split_at(name_start);
+    if !name.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let candidates: Vec<_> = COMMANDS
+        .iter()
+        .map(|(full, _)| *full)
+        .filter(|full| full.starts_with(name))
+        .collect();
+    let first = *candidates.first()?;
+    let common = candidates.iter().fold(first.len(), |len, c| {
+        first.bytes().zip(c.bytes()).take(len).take_while(|(a, b)| a == b).count()
+    });
+
+    let mut completed = format!("{}{}", prefix, &first[..common]);
+    if candidates.len() == 1 {
+        completed.push(' ');
+    }
+    if completed == trimmed {
+        return None;
+    }
+    Some(completed)
+}
+
+// Completes command names in the prompt of the command line
+pub struct CompleteCommand;
+
+impl prompt::Action for CompleteCommand {
+    fn new<W: Write>(_prompt: &mut Prompt<'_, W>) -> Self {
+        Self
+    }
+
+    fn complete(&mut self, input: &str) -> Option<String> {
+        complete(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn substitute(range: LineRange, pattern: &str, replacement: &str, global: bool) -> ExCommand {
+        ExCommand::Substitute {
+            range,
+            pattern: pattern.to_string(),
+            replacement: replacement.to_string(),
+            global,
+        }
+    }
+
+    #[test]
+    fn parse_commands_and_abbreviations() {
+        assert_eq!(parse("w"), Ok(ExCommand::Write(None)));
+        assert_eq!(parse(":write  out.txt "), Ok(ExCommand::Write(Some("out.txt".to_string()))));
+        assert_eq!(parse("sav new.txt"), Ok(ExCommand::SaveAs("new.txt".to_string())));
+        assert_eq!(parse("x"), Ok(ExCommand::WriteQuit));
+        assert_eq!(parse("q"), Ok(ExCommand::Quit { force: false }));
+        assert_eq!(parse("q!"), Ok(ExCommand::Quit { force: true }));
+        assert_eq!(parse("e src/main.rs"), Ok(ExCommand::Edit("src/main.rs".to_string())));
+        assert_eq!(parse("b 2"), Ok(ExCommand::Buffer("2".to_string())));
+        assert_eq!(parse("go $"), Ok(ExCommand::Goto(Address::Last)));
+    }
+
+    #[test]
+    fn parse_rejects_invalid_commands() {
+        assert_eq!(parse(""), Err("No command given".to_string()));
+        assert_eq!(parse("sa x"), Err("Unknown command :sa".to_string()));
+        assert_eq!(parse("wfoo"), Err("Unknown command :wfoo".to_string()));
+        assert_eq!(parse("e"), Err(":edit needs an argument".to_string()));
+        assert_eq!(parse("w!"), Err(":write does not take '!'".to_string()));
+        assert_eq!(parse("q now"), Err(":quit does not take an argument".to_string()));
+        assert_eq!(parse("1,2w"), Err(":write does not take a range".to_string()));
+        assert_eq!(parse("1,"), Err("Line number is expected after ','".to_string()));
+    }
+
+    #[test]
+    fn parse_line_numbers_and_ranges() {
+        assert_eq!(parse("42"), Ok(ExCommand::Goto(Address::Line(42))));
+        assert_eq!(parse(":$"), Ok(ExCommand::Goto(Address::Last)));
+        // A range jumps to its end
+        assert_eq!(parse("3, 7"), Ok(ExCommand::Goto(Address::Line(7))));
+        assert_eq!(parse("s/a/b/"), Ok(substitute(LineRange::CURRENT, "a", "b", false)));
+        let all = LineRange(Address::Line(1), Address::Last);
+        assert_eq!(parse("%s/a/b/g"), Ok(substitute(all, "a", "b", true)));
+        let to_last = LineRange(Address::Current, Address::Last);
+        assert_eq!(parse(".,$s/a/b/"), Ok(substitute(to_last, "a", "b", false)));
+        let lines = LineRange(Address::Line(2), Address::Line(4));
+        assert_eq!(parse("2 , 4 s/a/b/"), Ok(substitute(lines, "a", "b", false)));
+    }
+
+    #[test]
+    fn resolve_ranges() {
+        let range = |start, end| LineRange(start, end);
+        assert_eq!(range(Address::Line(1), Address::Last).resolve(3, 10), Ok((0, 9)));
+        assert_eq!(range(Address::Current, Address::Line(5)).resolve(3, 10), Ok((3, 4)));
+        assert_eq!(range(Address::Line(0), Address::Line(0)).resolve(3, 10), Ok((0, 0)));
+        // Line 1 exists even in an empty buffer
+        assert_eq!(range(Address::Line(1), Address::Last).resolve(0, 0), Ok((0, 0)));
+        assert_eq!(
+            range(Address::Line(2), Address::Line(11)).resolve(3, 10),
+            Err("Line 11 is out of range (1..10)".to_string()),
+        );
+        assert_eq!(
+            range(Address::Line(5), Address::Line(2)).resolve(3, 10),
+            Err("Backwards range given".to_string()),
+        );
+    }
+
+    #[test]
+    fn parse_substitute_delimiters_and_escapes() {
+        assert_eq!(parse_substitute("/a/b/g"), Ok(("a".to_string(), "b".to_string(), true)));
+        // Any punctuation is a delimiter, and the trailing delimiter is optional
+        assert_eq!(parse_substitute("#a/b#c/d"), Ok(("a/b".to_string(), "c/d".to_string(), false)));
+        assert_eq!(parse_substitute("/a/"), Ok(("a".to_string(), "".to_string(), false)));
+        // An escaped delimiter is taken literally. Other escapes are kept for the pattern.
+        assert_eq!(parse_substitute(r"/a\/b/c\/d/"), Ok(("a/b".to_string(), "c/d".to_string(), false)));
+        assert_eq!(parse_substitute(r"/a\tb/c\\/"), Ok((r"a\tb".to_string(), r"c\\".to_string(), false)));
+        assert_eq!(parse_substitute(r"/a/b\"), Ok(("a".to_string(), r"b\".to_string(), false)));
+        // Delimiters after the flags are part of the flags
+        assert_eq!(parse_substitute("/a/b/g/"), Err("Unknown flags \"g/\"".to_string()));
+        assert_eq!(parse_substitute("//b/"), Err("Empty pattern".to_string()));
+        assert_eq!(parse_substitute("xaxbx"), Err("Usage: :[range]s/pattern/replacement/[g]".to_string()));
+        assert_eq!(parse_substitute(""), Err("Usage: :[range]s/pattern/replacement/[g]".to_string()));
+    }
+
+    #[test]
+    fn parse_set_values() {
+        let set = |name: &str, value| Ok(ExCommand::Set(name.to_string(), value));
+        assert_eq!(parse("set wrap"), set("wrap", toml::Value::Boolean(true)));
+        assert_eq!(parse("set nowrap"), set("wrap", toml::Value::Boolean(false)));
+        assert_eq!(parse("se tab_width = 2"), set("tab_width", toml::Value::Integer(2)));
+        assert_eq!(parse("set expand_tab=false"), set("expand_tab", toml::Value::Boolean(false)));
+        assert_eq!(parse("set theme=\"light\""), set("theme", toml::Value::String("light".to_string())));
+        // Values which are not valid TOML are strings
+        assert_eq!(parse("set theme=light"), set("theme", toml::Value::String("light".to_string())));
+        assert_eq!(parse("set line_numbers="), set("line_numbers", toml::Value::String("".to_string())));
+        assert_eq!(parse("set"), Err("Usage: :set option[=value]".to_string()));
+    }
+
+    #[test]
+    fn parse_filter() {
+        let filter = |range, command: &str| {
+            Ok(ExCommand::Filter {
+                range,
+                command: command.to_string(),
+            })
+        };
+        assert_eq!(parse("!sort -u"), filter(None, "sort -u"));
+        let lines = LineRange(Address::Line(2), Address::Line(5));
+        assert_eq!(parse("2,5! tr a-z A-Z "), filter(Some(lines), "tr a-z A-Z"));
+        assert_eq!(parse("%!"), Err("Usage: :[range]!command".to_string()));
+    }
+
+    #[test]
+    fn complete_command_names() {
+        assert_eq!(complete("wr"), Some("write ".to_string()));
+        assert_eq!(complete("  sa"), Some("saveas ".to_string()));
+        // Nothing can be added to the common prefix of the candidates
+        assert_eq!(complete("s"), None);
+        assert_eq!(complete("w"), None);
+        assert_eq!(complete("g"), Some("goto ".to_string()));
+        // Ranges are kept
+        assert_eq!(complete("%su"), Some("%substitute ".to_string()));
+        assert_eq!(complete("1,3su"), Some("1,3substitute ".to_string()));
+        assert_eq!(complete("write "), None);
+        assert_eq!(complete("zz"), None);
+        assert_eq!(complete("e foo"), None);
+    }
+}
@@ -0,0 +1,165 @@
+use std::path::Path;
+
+// Kind of text in a buffer, detected from the file name. Editing behaviors such as auto-indent
+// depend on it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FileType {
+    Plain,
+    C,
+    Cpp,
+    Css,
+    Go,
+    Html,
+    Java,
+    JavaScript,
+    Json,
+    Lua,
+    Markdown,
+    Python,
+    Rust,
+    Shell,
+    Sql,
+    Toml,
+    TypeScript,
+    Yaml,
+}
+
+impl FileType {
+    pub fn detect<P: AsRef<Path>>(path: P) -> Self {
+        use FileType::*;
+        let ext = match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some(ext) => ext.to_ascii_lowercase(),
+            None => return Plain,
+        };
+        match ext.as_str() {
+            "c" | "h" => C,
+            "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => Cpp,
+            "css" => Css,
+            "go" => Go,
+            "html" | "htm" => Html,
+            "java" => Java,
+            "js" | "jsx" | "mjs" | "cjs" => JavaScript,
+            "json" => Json,
+            "lua" => Lua,
+            "md" | "markdown" => Markdown,
+            "py" | "pyi" => Python,
+            "rs" => Rust,
+            "sh" | "bash" | "zsh" => Shell,
+            "sql" => Sql,
+            "toml" => Toml,
+            "ts" | "tsx" => TypeScript,
+            "yaml" | "yml" => Yaml,
+            _ => Plain,
+        }
+    }
+
+    // Name used in the configuration file, e.g. `rust` in `[auto_pairs]`
+    pub fn from_name(name: &str) -> Option<Self> {
+        use FileType::*;
+        let ft = match name {
+            "plain" => Plain,
+            "c" => C,
+            "cpp" => Cpp,
+            "css" => Css,
+            "go" => Go,
+            "html" => Html,
+            "java" => Java,
+            "javascript" => JavaScript,
+            "json" => Json,
+            "lua" => Lua,
+            "markdown" => Markdown,
+            "python" => Python,
+            "rust" => Rust,
+            "shell" => Shell,
+            "sql" => Sql,
+            "toml" => Toml,
+            "typescript" => TypeScript,
+            "yaml" => Yaml,
+            _ => return None,
+        };
+        Some(ft)
+    }
+
+    pub fn line_comment(self) -> Option<&'static str> {
+        use FileType::*;
+        match self {
+            C | Cpp | Go | Java | JavaScript | Rust | TypeScript => Some("//"),
+            Python | Shell | Toml | Yaml => Some("#"),
+            Lua | Sql => Some("--"),
+            Plain | Css | Html | Json | Markdown => None,
+        }
+    }
+
+    pub fn block_comment(self) -> Option<(&'static str, &'static str)> {
+        use FileType::*;
+        match self {
+            C | Cpp | Css | Go | Java | JavaScript | Rust | Sql | TypeScript => Some(("/*", "*/")),
+            Html | Markdown => Some(("<!--", "-->")),
+            Lua => Some(("--[[", "]]")),
+            Plain | Json | Python | Shell | Toml | Yaml => None,
+        }
+    }
+
+    // Quotes of string literals. Strings don't continue to the next line.
+    pub fn quotes(self) -> &'static [char] {
+        use FileType::*;
+        match self {
+            Plain | Html | Markdown => &[],
+            Json => &['"'],
+            JavaScript | TypeScript => &['"', '\'', '`'],
+            Go => &['"', '`'],
+            // Single quotes are character literals such as 'a' or '\n'
+            C | Cpp | Java | Rust => &['"'],
+            Css | Lua | Python | Shell | Sql | Toml | Yaml => &['"', '\''],
+        }
+    }
+
+    // Whether single quotes make character literals, which are distinguished from Rust lifetimes
+    // by the closing quote
+    pub fn char_literals(self) -> bool {
+        use FileType::*;
+        matches!(self, C | Cpp | Go | Java | Rust)
+    }
+
+    // Opening and closing characters inserted together by auto-pairing
+    pub fn pairs(self) -> &'static [(char, char)] {
+        use FileType::*;
+        const PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')];
+        const PAIRS_WITH_QUOTE: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')];
+        match self {
+            // Single quotes are apostrophes in prose and lifetimes in Rust
+            Plain | Html | Json | Markdown | Rust => PAIRS,
+            _ => PAIRS_WITH_QUOTE,
+        }
+    }
+
+    // A newline typed after one of these characters indents the new line one more level
+    pub fn indent_triggers(self) -> &'static [char] {
+        use FileType::*;
+        match self {
+            Plain | Html | Markdown => &[],
+            Python => &[':', '(', '[', '{'],
+            Yaml => &[':', '[', '{'],
+            _ => &['(', '[', '{'],
+        }
+    }
+
+    // Typing one of these characters at the indentation of a line dedents the line one level
+    pub fn dedent_triggers(self) -> &'static [char] {
+        use FileType::*;
+        match self {
+            Plain | Html | Markdown => &[],
+            Yaml => &[']', '}'],
+            _ => &[')', ']', '}'],
+        }
+    }
+}
+
+pub fn closing_bracket(open: char) -> Option<char> {
+    match open {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        _ => None,
+    }
+}
@@ -0,0 +1,50 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+
+// Runs the shell command with the text as its stdin and returns its stdout. A non-zero exit status
+// or any output to stderr is an error so that a failing command never replaces text.
+pub fn run(command: &str, input: &str) -> Result<String, String> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Could not run {:?}: {}", command, err))?;
+
+    // Written in another thread since the command may not read all input before writing output
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_string();
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("Could not run {:?}: {}", command, err))?;
+    // The command may exit without reading stdin. It is not an error.
+    let _ = writer.join();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim();
+    if !output.status.success() {
+        let status = match output.status.code() {
+            Some(code) => format!("exit status {}", code),
+            None => "a signal".to_string(),
+        };
+        return Err(if stderr.is_empty() {
+            format!("{:?} failed with {}", command, status)
+        } else {
+            format!("{:?} failed with {}: {}", command, status, stderr)
+        });
+    }
+    if !stderr.is_empty() {
+        return Err(format!("{:?}: {}", command, stderr));
+    }
+
+    let stdout = String::from_utf8(output.stdout).map_err(|_| format!("Output of {:?} is not UTF-8", command))?;
+    if let Some(c) = stdout.chars().find(|&c| c.is_control() && c != '\t' && c != '\n') {
+        return Err(format!("Output of {:?} contains control character {:?}", command, c));
+    }
+    Ok(stdout)
+}
@@ -0,0 +1,291 @@
+use crate::term_color::{Color, Theme};
+
+use std::io::{self, Write};
+use unicode_width::UnicodeWidthChar;
+
+// A wide character takes its cell and the following ones, which are left with zero width
+#[derive(Clone, PartialEq)]
+struct Cell {
+    ch: char,
+    // Zero-width characters such as combining marks drawn over `ch`
+    combining: Vec<char>,
+    width: usize,
+    color: Color,
+}
+
+impl Cell {
+    fn blank() -> Self {
+        Self {
+            ch: ' ',
+            combining: vec![],
+            width: 1,
+            color: Color::Reset,
+        }
+    }
+
+    fn continuation(color: Color) -> Self {
+        Self {
+            ch: ' ',
+            combining: vec![],
+            width: 0,
+            color,
+        }
+    }
+}
+
+// Contents of the whole screen as cells. Drawing only updates the cells, and the terminal is
+// updated afterwards by sending the cells which differ from the frame it shows.
+pub struct Grid {
+    width: usize,
+    height: usize,
+    cells: Vec<Cell>,
+    // Where the next character is put, and its color
+    row: usize,
+    col: usize,
+    color: Color,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+impl Grid {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![Cell::blank(); width * height],
+            row: 0,
+            col: 0,
+            color: Color::Reset,
+        }
+    }
+
+    pub fn move_to(&mut self, row: usize, col: usize) {
+        self.row = row;
+        self.col = col;
+    }
+
+    pub fn set_color(&mut self, color: Color) {
+        self.color = color;
+    }
+
+    pub fn put_str(&mut self, s: &str) {
+        for c in s.chars() {
+            self.put_char(c, c.width_cjk().unwrap_or(1));
+        }
+    }
+
+    // The width is given since some characters such as box drawings are ambiguous in width. A
+    // character which does not fit in the rest of the line is not drawn.
+    pub fn put_char(&mut self, c: char, width: usize) {
+        if self.row >= self.height {
+            return;
+        }
+
+        if width == 0 {
+            if let Some(head) = self.head_of(self.col.wrapping_sub(1)) {
+                self.cells[self.row * self.width + head].combining.push(c);
+            }
+            return;
+        }
+
+        if self.col + width > self.width {
+            while self.col < self.width {
+                self.put_char(' ', 1);
+            }
+            return;
+        }
+
+        // Halves of wide characters partially overwritten are left as blanks
+        if let Some(head) = self.head_of(self.col) {
+            if head < self.col {
+                self.blank_from(head);
+            }
+        }
+        let end = self.col + width;
+        if end < self.width && self.cells[self.row * self.width + end].width == 0 {
+            self.blank_from(end);
+        }
+
+        let idx = self.row * self.width + self.col;
+        self.cells[idx] = Cell {
+            ch: c,
+            combining: vec![],
+            width,
+            color: self.color,
+        };
+        for cell in &mut self.cells[idx + 1..idx + width] {
+            *cell = Cell::continuation(self.color);
+        }
+        self.col = end;
+    }
+
+    // Fills the rest of the line with blanks like "\x1b[K"
+    pub fn clear_line(&mut self) {
+        let color = self.color;
+        self.color = Color::Reset;
+        while self.col < self.width {
+            self.put_char(' ', 1);
+        }
+        self.color = color;
+    }
+
+    // Column of the character covering the column in the current row
+    fn head_of(&self, col: usize) -> Option<usize> {
+        if col >= self.width {
+            return None;
+        }
+        let line = &self.cells[self.row * self.width..(self.row + 1) * self.width];
+        (0..=col).rev().find(|&c| line[c].width > 0)
+    }
+
+    // Replaces the character at the column and the cells it covers with blanks
+    fn blank_from(&mut self, col: usize) {
+        let line = &mut self.cells[self.row * self.width..(self.row + 1) * self.width];
+        line[col] = Cell::blank();
+        for cell in line[col + 1..].iter_mut().take_while(|c| c.width == 0) {
+            *cell = Cell::blank();
+        }
+    }
+
+    // Writes sequences which update the terminal showing `prev` to this frame, and makes `prev`
+    // the same as this frame. Both grids must have the same size.
+    pub fn diff<W: Write>(&self, prev: &mut Grid, theme: Theme, mut out: W) -> io::Result<()> {
+        // Position of the terminal cursor when it is known
+        let mut pos = None;
+        let mut color = Color::Reset;
+
+        for row in 0..self.height {
+            for col in 0..self.width {
+                let idx = row * self.width + col;
+                let cell = &self.cells[idx];
+                if cell.width == 0 || *cell == prev.cells[idx] {
+                    continue;
+                }
+
+                if pos != Some((row, col)) {
+                    write!(out, "\x1b[{};{}H", row + 1, col + 1)?;
+                }
+                if cell.color != color {
+                    out.write_all(theme.sequence(Color::Reset))?;
+                    if cell.color != Color::Reset {
+                        out.write_all(theme.sequence(cell.color))?;
+                    }
+                    color = cell.color;
+                }
+                write!(out, "{}", cell.ch)?;
+                for c in cell.combining.iter() {
+                    write!(out, "{}", c)?;
+                }
+
+                // The terminal may move the cursor differently after an ambiguous character or
+                // at the end of line
+                let next = col + cell.width;
+                pos = if next < self.width && cell.ch.width() == Some(cell.width) {
+                    Some((row, next))
+                } else {
+                    None
+                };
+                prev.cells[idx..idx + cell.width].clone_from_slice(&self.cells[idx..idx + cell.width]);
+            }
+        }
+
+        if color != Color::Reset {
+            out.write_all(theme.sequence(Color::Reset))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diff(grid: &Grid, prev: &mut Grid) -> String {
+        let mut out = vec![];
+        grid.diff(prev, Theme::Dark, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn draw(grid: &mut Grid, row: usize, col: usize, s: &str) {
+        grid.move_to(row, col);
+        grid.put_str(s);
+    }
+
+    #[test]
+    fn diff_of_same_frames_is_empty() {
+        let mut grid = Grid::new(10, 3);
+        draw(&mut grid, 1, 2, "abc");
+        let mut prev = Grid::new(10, 3);
+        diff(&grid, &mut prev);
+        assert_eq!(diff(&grid, &mut prev), "");
+    }
+
+    #[test]
+    fn diff_sends_only_changed_cells() {
+        let mut prev = Grid::new(10, 3);
+        let mut grid = Grid::new(10, 3);
+        draw(&mut grid, 0, 0, "abcdef");
+        assert_eq!(diff(&grid, &mut prev), "\x1b[1;1Habcdef");
+
+        draw(&mut grid, 0, 1, "X");
+        draw(&mut grid, 0, 4, "YZ");
+        draw(&mut grid, 2, 9, "!");
+        assert_eq!(diff(&grid, &mut prev), "\x1b[1;2HX\x1b[1;5HYZ\x1b[3;10H!");
+    }
+
+    #[test]
+    fn diff_switches_colors() {
+        let mut prev = Grid::new(10, 1);
+        let mut grid = Grid::new(10, 1);
+        draw(&mut grid, 0, 0, "a");
+        grid.set_color(Color::Error);
+        grid.put_str("b");
+        grid.set_color(Color::Reset);
+        grid.put_str("c");
+        assert_eq!(diff(&grid, &mut prev), "\x1b[1;1Ha\x1b[m\x1b[91mb\x1b[mc");
+
+        // The color is reset at the end of a frame
+        grid.move_to(0, 1);
+        grid.set_color(Color::NonText);
+        grid.put_str("~");
+        assert_eq!(diff(&grid, &mut prev), "\x1b[1;2H\x1b[m\x1b[90m~\x1b[m");
+    }
+
+    #[test]
+    fn diff_wide_characters() {
+        let mut prev = Grid::new(6, 1);
+        let mut grid = Grid::new(6, 1);
+        draw(&mut grid, 0, 0, "aあb");
+        assert_eq!(diff(&grid, &mut prev), "\x1b[1;1Haあb");
+
+        // Overwriting the second half of the wide character blanks the first half
+        draw(&mut grid, 0, 2, "x");
+        assert_eq!(diff(&grid, &mut prev), "\x1b[1;2H x");
+
+        // A wide character which does not fit at the end of the line is not drawn
+        draw(&mut grid, 0, 5, "い");
+        assert_eq!(diff(&grid, &mut prev), "");
+    }
+
+    #[test]
+    fn diff_moves_cursor_after_ambiguous_characters() {
+        let mut prev = Grid::new(6, 1);
+        let mut grid = Grid::new(6, 1);
+        grid.move_to(0, 0);
+        grid.put_char('─', 2);
+        grid.put_str("ab");
+        assert_eq!(diff(&grid, &mut prev), "\x1b[1;1H─\x1b[1;3Hab");
+    }
+
+    #[test]
+    fn diff_combining_characters_with_their_base() {
+        let mut prev = Grid::new(4, 1);
+        let mut grid = Grid::new(4, 1);
+        draw(&mut grid, 0, 0, "e\u{301}x");
+        assert_eq!(diff(&grid, &mut prev), "\x1b[1;1He\u{301}x");
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    Relative,
+    // Absolute number on the cursor line and relative numbers on the others
+    Hybrid,
+}
+
+impl LineNumbers {
+    pub fn next(self) -> Self {
+        use LineNumbers::*;
+        match self {
+            Off => Absolute,
+            Absolute => Relative,
+            Relative => Hybrid,
+            Hybrid => Off,
+        }
+    }
+
+    // Relative numbers need to be redrawn whenever the cursor line changes
+    pub fn depends_on_cursor(self) -> bool {
+        matches!(self, LineNumbers::Relative | LineNumbers::Hybrid)
+    }
+}
+
+impl fmt::Display for LineNumbers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LineNumbers::*;
+        match self {
+            Off => write!(f, "off"),
+            Absolute => write!(f, "absolute"),
+            Relative => write!(f, "relative"),
+            Hybrid => write!(f, "hybrid"),
+        }
+    }
+}
+
+// Columns drawn on the left of text in each window. It consists of a sign column reserved for
+// markers such as diagnostics or diff status, and a line number column.
+#[derive(Clone, Copy)]
+pub struct Gutter {
+    pub line_numbers: LineNumbers,
+    pub sign_width: usize,
+}
+
+impl Default for Gutter {
+    fn default() -> Self {
+        Self {
+            line_numbers: LineNumbers::Off,
+            sign_width: 0,
+        }
+    }
+}
+
+impl Gutter {
+    fn number_width(&self, num_lines: usize) -> usize {
+        if self.line_numbers == LineNumbers::Off {
+            return 0;
+        }
+        let mut digits = 1;
+        let mut n = num_lines;
+        while n >= 10 {
+            n /= 10;
+            digits += 1;
+        }
+        // Trailing space between numbers and text
+        digits + 1
+    }
+
+    pub fn width(&self, num_lines: usize) -> usize {
+        self.sign_width + self.number_width(num_lines)
+    }
+
+    // Label for the line at `line` (0-origin) when the cursor is at line `cy`. `None` means the
+    // line is not in the buffer.
+    pub fn label(&self, line: Option<usize>, cy: usize, width: usize) -> String {
+        let number_width = width - self.sign_width;
+        let mut s = " ".repeat(self.sign_width);
+        if number_width == 0 {
+            return s;
+        }
+
+        let number = line.map(|line| {
+            let relative = line.abs_diff(cy);
+            match self.line_numbers {
+                LineNumbers::Off | LineNumbers::Absolute => line + 1,
+                LineNumbers::Relative => relative,
+                LineNumbers::Hybrid if line == cy => line + 1,
+                LineNumbers::Hybrid => relative,
+            }
+        });
+
+        match number {
+            // The cursor line is left aligned in hybrid mode as Vim does
+            Some(n) if self.line_numbers == LineNumbers::Hybrid && line == Some(cy) => {
+                s.push_str(&format!("{:<w$} ", n, w = number_width - 1))
+            }
+            Some(n) => s.push_str(&format!("{:>w$} ", n, w = number_width - 1)),
+            None => s.push_str(&" ".repeat(number_width)),
+        }
+        s
+    }
+}
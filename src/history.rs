@@ -5,31 +5,51 @@ use std::cmp;
 use std::collections::VecDeque;
 use std::mem;
 
-const MAX_ENTRIES: usize = 1000;
+pub const MAX_ENTRIES: usize = 1000;
 
 pub type Edit = Vec<EditDiff>;
 
-#[derive(Default)]
 pub struct History {
     index: usize,
     ongoing: Edit,
     entries: VecDeque<Edit>,
+    max_entries: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            index: 0,
+            ongoing: vec![],
+            entries: VecDeque::new(),
+            max_entries: MAX_ENTRIES,
+        }
+    }
 }
 
 impl History {
+    // Drops the oldest entries when the new limit is smaller than the current number of entries
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        while self.entries.len() > max_entries {
+            self.entries.pop_front();
+            self.index = self.index.saturating_sub(1);
+        }
+    }
+
     pub fn push(&mut self, diff: EditDiff) {
         self.ongoing.push(diff);
     }
 
     pub fn finish_ongoing_edit(&mut self) -> bool {
-        debug_assert!(self.entries.len() <= MAX_ENTRIES);
+        debug_assert!(self.entries.len() <= self.max_entries);
         if self.ongoing.is_empty() {
             return false;
         }
 
         let diffs = mem::take(&mut self.ongoing);
 
-        if self.entries.len() == MAX_ENTRIES {
+        if self.entries.len() == self.max_entries {
             self.entries.pop_front();
             self.index -= 1;
         }
@@ -43,25 +63,25 @@ impl History {
         true
     }
     
-    pub fn undo(&mut self, rows: &mut Vec<Row>) -> Option<(usize, usize, usize, bool)> {
+    pub fn undo(&mut self, rows: &mut Vec<Row>, tab_stop: usize) -> Option<(usize, usize, usize, bool)> {
         let edited = self.finish_ongoing_edit();
         if self.index == 0 {
             return None;
         }
         self.index -= 1;
         let i = self.entries[self.index].iter().rev();
-        let (x, y, dirty_start) = Self::apply_diffs(i, UndoRedo::Undo, rows);
+        let (x, y, dirty_start) = Self::apply_diffs(i, UndoRedo::Undo, rows, tab_stop);
         Some((x, y, dirty_start, edited))       
     }
 
-    pub fn redo(&mut self, rows: &mut Vec<Row>) -> Option<(usize, usize, usize, bool)> {
+    pub fn redo(&mut self, rows: &mut Vec<Row>, tab_stop: usize) -> Option<(usize, usize, usize, bool)> {
         let edited = self.finish_ongoing_edit();
         if self.index == self.entries.len() {
             return None;
         }
         self.index += 1;
         let i = self.entries[self.index - 1].iter();
-        let (x, y, dirty_start) = Self::apply_diffs(i, UndoRedo::Redo, rows);
+        let (x, y, dirty_start) = Self::apply_diffs(i, UndoRedo::Redo, rows, tab_stop);
         Some((x, y, dirty_start, edited))       
     }
 
@@ -69,9 +89,10 @@ impl History {
         diffs: I,
         which: UndoRedo,
         rows: &mut Vec<Row>,
+        tab_stop: usize,
     ) -> (usize, usize, usize) {
         diffs.fold((0, 0, usize::MAX), |(_, _, dirty_start), diff| {
-            let (x, y) = diff.apply(rows, which);
+            let (x, y) = diff.apply(rows, which, tab_stop);
             (x, y, cmp::min(dirty_start, y))
         })
     }
@@ -1,9 +1,12 @@
 use crate::error::{Error, Result};
+use crate::signal::SigwinchWaker;
 
 use std::fmt;
-use std::io::{self, Read};
+use std::io;
 use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
 use std::str;
+use std::time::Duration;
 
 use crossterm::{terminal};
 
@@ -19,8 +22,14 @@ impl StdinRawMode {
         Ok(StdinRawMode { stdin })
     }    
 
-    pub fn input_keys(self) -> InputSequences {
-        InputSequences { stdin: self }
+    pub fn input_keys(self) -> Result<InputSequences> {
+        Ok(InputSequences {
+            stdin: self,
+            sigwinch: SigwinchWaker::new()?,
+            buf: [0; 256],
+            len: 0,
+            pos: 0,
+        })
     }
 }
 
@@ -46,7 +55,7 @@ impl DerefMut for StdinRawMode {
     }
 }
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub enum KeySeq {
     Unidentified,
     Utf8Key(char),
@@ -56,6 +65,9 @@ pub enum KeySeq {
     UpKey,
     DownKey,
     DeleteKey,
+    PageUpKey,
+    PageDownKey,
+    BackTabKey,
     Cursor(usize, usize),
 }
 
@@ -72,12 +84,15 @@ impl fmt::Display for KeySeq {
             UpKey => write!(f, "UP"),
             DownKey => write!(f, "DOWN"),
             DeleteKey => write!(f, "DELETE"),
+            PageUpKey => write!(f, "PAGEUP"),
+            PageDownKey => write!(f, "PAGEDOWN"),
+            BackTabKey => write!(f, "BACKTAB"),
             Cursor(r, c) => write!(f, "CURSOR({}, {})", r, c),
         }
     }
 }
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct InputSeq {
     pub key: KeySeq,
     pub ctrl: bool,
@@ -102,26 +117,95 @@ impl InputSeq {
     }
 }
 
+// While no key is typed, `Unidentified` is sent at this interval so that the editor can run
+// timers such as expiring messages
+const IDLE_TICK: Duration = Duration::from_millis(100);
+
+// Bytes of an escape sequence sent for a key arrive together. ESC with no byte within this time is
+// the ESC key itself.
+const ESC_TIMEOUT: Duration = Duration::from_millis(30);
+
+// Bytes are read from the file descriptor directly rather than through the buffer of `io::Stdin`
+// so that polling it does not miss bytes already buffered. Waiting for input is also interrupted
+// when the terminal is resized so that the editor can redraw the screen at once.
 pub struct InputSequences {
     stdin: StdinRawMode,
+    sigwinch: SigwinchWaker,
+    buf: [u8; 256],
+    len: usize,
+    pos: usize,
 }
 
 impl InputSequences {
+    // Returns false when no input arrives within the timeout or the terminal is resized
+    fn wait_input(&mut self, timeout: Duration) -> Result<bool> {
+        if self.pos < self.len {
+            return Ok(true);
+        }
+        let mut fds = [
+            libc::pollfd {
+                fd: self.stdin.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.sigwinch.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let timeout = timeout.as_millis() as libc::c_int;
+        let ret = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(err.into());
+        }
+        if fds[1].revents & libc::POLLIN != 0 {
+            self.sigwinch.drain();
+            return Ok(false);
+        }
+        Ok(fds[0].revents != 0)
+    }
+
     fn read_byte(&mut self) -> Result<Option<u8>> {
-        let mut one_byte: [u8; 1] = [0];
-        Ok(if self.stdin.read(&mut one_byte)? == 0 {
-            None
-        } else {                
-            Some(one_byte[0])
-        })
+        if self.pos == self.len {
+            let fd = self.stdin.as_raw_fd();
+            let ret = loop {
+                let ret = unsafe { libc::read(fd, self.buf.as_mut_ptr() as *mut libc::c_void, self.buf.len()) };
+                if ret >= 0 {
+                    break ret as usize;
+                }
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err.into());
+                }
+            };
+            if ret == 0 {
+                return Ok(None);
+            }
+            self.len = ret;
+            self.pos = 0;
+        }
+        let b = self.buf[self.pos];
+        self.pos += 1;
+        Ok(Some(b))
     }
 
     fn decode_escape_sequence(&mut self) -> Result<InputSeq> {
         use KeySeq::*;
 
+        // ESC alone is not followed by any byte. Reading one more byte would block until the next
+        // key is typed.
+        if !self.wait_input(ESC_TIMEOUT)? {
+            return Ok(InputSeq::new(Key(0x1b)));
+        }
+
         match self.read_byte()? {
             Some(b'[') => { /* fall through */ }
-            Some(b) if b.is_ascii_control() => {
+            Some(b) if b.is_ascii_control() && b != 0x7f => {
                 return Ok(InputSeq::new(Key(0x1b)));
             }
             Some(b) => {
@@ -137,7 +221,7 @@ impl InputSequences {
             if let Some(b) = self.read_byte()? {
                 match b {
                     b'A' | b'B' | b'C' | b'D' | b'F' | b'H' | b'K' | b'J' | b'R' | b'c' | b'f'
-                    | b'g' | b'h' | b'l' | b'm' | b'n' | b'q' | b't' | b'y' | b'~' => break b,
+                    | b'g' | b'h' | b'l' | b'm' | b'n' | b'q' | b't' | b'y' | b'Z' | b'~' => break b,
                     _ => buf.push(b),
                 }
             } else {
@@ -155,15 +239,26 @@ impl InputSequences {
                     b'D' => LeftKey,
                     _ => unreachable!(),
                 };
-                let ctrl = args.next() == Some(b"1") && args.next() == Some(b"5");
-                let alt = false;
+                let modifier = match (args.next(), args.next()) {
+                    (Some(b"1"), Some(m)) => m,
+                    _ => b"1",
+                };
+                let ctrl = modifier == b"5" || modifier == b"7";
+                let alt = modifier == b"3" || modifier == b"7";
                 Ok(InputSeq { key, ctrl, alt })
             }
+            b'Z' => Ok(InputSeq::new(BackTabKey)),
             b'~' => {
-                match args.next() {
-                    Some(b"3") => Ok(InputSeq::new(DeleteKey)),
-                    _ => Ok(InputSeq::new(Unidentified)),
-                }
+                let key = match args.next() {
+                    Some(b"3") => DeleteKey,
+                    Some(b"5") => PageUpKey,
+                    Some(b"6") => PageDownKey,
+                    _ => return Ok(InputSeq::new(Unidentified)),
+                };
+                let modifier = args.next().unwrap_or(b"1");
+                let ctrl = modifier == b"5" || modifier == b"7";
+                let alt = modifier == b"3" || modifier == b"7";
+                Ok(InputSeq { key, ctrl, alt })
             }
             _ => unreachable!(),
         }
@@ -215,6 +310,9 @@ impl InputSequences {
     }
     
     fn read_seq(&mut self) -> Result<InputSeq> {
+        if !self.wait_input(IDLE_TICK)? {
+            return Ok(InputSeq::new(KeySeq::Unidentified));
+        }
         if let Some(b) = self.read_byte()? {
             self.decode(b)
         } else {
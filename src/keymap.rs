@@ -0,0 +1,710 @@
+use crate::input::{InputSeq, KeySeq};
+use std::collections::HashMap;
+use std::fmt;
+
+macro_rules! commands {
+    ($($variant:ident => $name:literal,)+) => {
+        #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+        pub enum Command {
+            $($variant,)+
+        }
+
+        impl Command {
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $($name => Some(Command::$variant),)+
+                    _ => None,
+                }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Command::$variant => $name,)+
+                }
+            }
+        }
+    };
+}
+
+// Names on the right side are used in the `[keys.*]` tables of the configuration file
+commands! {
+    Save => "save",
+    Quit => "quit",
+    Undo => "undo",
+    Redo => "redo",
+    InsertLine => "insert-line",
+    DeleteChar => "delete-char",
+    DeleteRightChar => "delete-right-char",
+    CursorUp => "cursor-up",
+    CursorDown => "cursor-down",
+    CursorLeft => "cursor-left",
+    CursorRight => "cursor-right",
+    LineStart => "line-start",
+    LineEnd => "line-end",
+    ForwardWord => "forward-word",
+    BackwardWord => "backward-word",
+    KillLine => "kill-line",
+    KillWord => "kill-word",
+    BackwardKillWord => "backward-kill-word",
+    DuplicateLines => "duplicate-lines",
+    DeleteLines => "delete-lines",
+    MoveLinesUp => "move-lines-up",
+    MoveLinesDown => "move-lines-down",
+    JoinLines => "join-lines",
+    OpenLineBelow => "open-line-below",
+    OpenLineAbove => "open-line-above",
+    PageDown => "page-down",
+    PageUp => "page-up",
+    HalfPageDown => "half-page-down",
+    HalfPageUp => "half-page-up",
+    ScrollViewDown => "scroll-view-down",
+    ScrollViewUp => "scroll-view-up",
+    Recenter => "recenter",
+    Yank => "yank",
+    ToggleMark => "toggle-mark",
+    ClearCursors => "clear-cursors",
+    Indent => "indent",
+    Dedent => "dedent",
+    AddCursorAtNextMatch => "add-cursor-at-next-match",
+    AddCursorAbove => "add-cursor-above",
+    AddCursorBelow => "add-cursor-below",
+    SplitSelectionIntoLines => "split-selection-into-lines",
+    SplitHorizontal => "split-horizontal",
+    SplitVertical => "split-vertical",
+    CloseWindow => "close-window",
+    NextWindow => "next-window",
+    PreviousWindow => "previous-window",
+    GrowWindow => "grow-window",
+    ShrinkWindow => "shrink-window",
+    ToggleWrap => "toggle-wrap",
+    ToggleExpandTab => "toggle-expand-tab",
+    CycleLineNumbers => "cycle-line-numbers",
+    ReloadConfig => "reload-config",
+    StartMacro => "start-macro",
+    StopMacro => "stop-macro",
+    PlayMacro => "play-macro",
+    RepeatMacro => "repeat-macro",
+    SaveMacro => "save-macro",
+    LoadMacro => "load-macro",
+    OpenCommandLine => "command-line",
+    FilterThroughShell => "filter-through-shell",
+    JumpToMatchingBracket => "jump-to-matching-bracket",
+    ToggleComment => "toggle-comment",
+    Accept => "accept",
+    // Also cancels a pending key sequence in any context
+    Cancel => "cancel",
+    DeleteWordBackward => "delete-word-backward",
+    Complete => "complete",
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Set of default key bindings chosen with `key_profile` in the configuration file
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum KeyProfile {
+    Default,
+    // Emacs-style movement and editing keys on top of the default bindings
+    Emacs,
+    // Modal editing like Vi. The default bindings are used in insert mode.
+    Vi,
+}
+
+impl KeyProfile {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(KeyProfile::Default),
+            "emacs" => Some(KeyProfile::Emacs),
+            "vi" => Some(KeyProfile::Vi),
+            _ => None,
+        }
+    }
+}
+
+// Where key bindings are looked up. Each context has its own set of bindings.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum KeyContext {
+    // Editing text in a buffer
+    Editor,
+    // Typing in the prompt on the message bar
+    Prompt,
+}
+
+impl KeyContext {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "editor" => Some(KeyContext::Editor),
+            "prompt" => Some(KeyContext::Prompt),
+            _ => None,
+        }
+    }
+
+    fn accepts(self, cmd: Command) -> bool {
+        use Command::*;
+        let prompt_only = matches!(cmd, Accept | DeleteWordBackward | Complete);
+        match self {
+            KeyContext::Editor => !prompt_only,
+            KeyContext::Prompt => prompt_only || matches!(cmd, Cancel | DeleteChar),
+        }
+    }
+}
+
+impl fmt::Display for KeyContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyContext::Editor => write!(f, "editor"),
+            KeyContext::Prompt => write!(f, "prompt"),
+        }
+    }
+}
+
+const EDITOR_KEYS: &[(&str, Command)] = {
+    use Command::*;
+    &[
+        ("C-s", Save),
+        ("C-q", Quit),
+        ("C-u", Undo),
+        ("C-r", Redo),
+        ("Enter", InsertLine),
+        ("C-h", DeleteChar),
+        ("Backspace", DeleteChar),
+        ("C-d", DeleteRightChar),
+        ("Delete", DeleteRightChar),
+        ("Up", CursorUp),
+        ("Down", CursorDown),
+        ("Left", CursorLeft),
+        ("Right", CursorRight),
+        ("C-Left", BackwardWord),
+        ("C-Right", ForwardWord),
+        ("M-d", KillWord),
+        ("C-w", BackwardKillWord),
+        ("M-Backspace", BackwardKillWord),
+        ("C-x d", DuplicateLines),
+        ("M-k", DeleteLines),
+        ("C-M-Up", MoveLinesUp),
+        ("C-M-Down", MoveLinesDown),
+        ("M-j", JoinLines),
+        ("C-o", OpenLineBelow),
+        ("C-x C-o", OpenLineAbove),
+        ("PageDown", PageDown),
+        ("PageUp", PageUp),
+        ("M-PageDown", HalfPageDown),
+        ("M-PageUp", HalfPageUp),
+        ("C-Down", ScrollViewDown),
+        ("C-Up", ScrollViewUp),
+        ("C-l", Recenter),
+        ("C-Space", ToggleMark),
+        ("Esc", ClearCursors),
+        ("C-g", Cancel),
+        ("Tab", Indent),
+        ("BackTab", Dedent),
+        ("M-n", AddCursorAtNextMatch),
+        ("M-Up", AddCursorAbove),
+        ("M-Down", AddCursorBelow),
+        ("M-l", SplitSelectionIntoLines),
+        ("M-s", SplitHorizontal),
+        ("M-v", SplitVertical),
+        ("M-c", CloseWindow),
+        ("C-x 2", SplitHorizontal),
+        ("C-x 3", SplitVertical),
+        ("C-x 0", CloseWindow),
+        ("M-o", NextWindow),
+        ("C-x o", NextWindow),
+        ("M-p", PreviousWindow),
+        ("M-=", GrowWindow),
+        ("M--", ShrinkWindow),
+        ("M-w", ToggleWrap),
+        ("M-t", ToggleExpandTab),
+        ("M-g", CycleLineNumbers),
+        ("M-r", ReloadConfig),
+        ("C-x (", StartMacro),
+        ("C-x )", StopMacro),
+        ("C-x e", PlayMacro),
+        ("C-x C-k r", RepeatMacro),
+        ("C-x C-k s", SaveMacro),
+        ("C-x C-k l", LoadMacro),
+        ("M-x", OpenCommandLine),
+        ("M-|", FilterThroughShell),
+        ("M-m", JumpToMatchingBracket),
+        ("M-;", ToggleComment),
+    ]
+};
+
+const EMACS_KEYS: &[(&str, Command)] = {
+    use Command::*;
+    &[
+        ("C-a", LineStart),
+        ("C-e", LineEnd),
+        ("C-f", CursorRight),
+        ("C-b", CursorLeft),
+        ("C-n", CursorDown),
+        ("C-p", CursorUp),
+        ("C-v", PageDown),
+        ("M-v", PageUp),
+        ("M-f", ForwardWord),
+        ("M-b", BackwardWord),
+        ("C-k", KillLine),
+        ("C-y", Yank),
+        ("C-x C-s", Save),
+        ("C-x C-c", Quit),
+    ]
+};
+
+const PROMPT_KEYS: &[(&str, Command)] = {
+    use Command::*;
+    &[
+        ("Enter", Accept),
+        ("C-g", Cancel),
+        ("C-q", Cancel),
+        ("Esc", Cancel),
+        ("C-h", DeleteChar),
+        ("Backspace", DeleteChar),
+        ("Delete", DeleteChar),
+        ("C-w", DeleteWordBackward),
+        ("Tab", Complete),
+    ]
+};
+
+// Parses key notation such as "C-s", "M-Up", "C-M-x", "Enter" or "a". "C-" is Ctrl and "M-" is
+// Alt (Meta). Keys which a terminal cannot send (e.g. "C-1") are rejected.
+pub fn parse_key(notation: &str) -> Result<InputSeq, String> {
+    use KeySeq::*;
+
+    let mut seq = InputSeq::new(Unidentified);
+    let mut rest = notation;
+    while rest.len() > 2 {
+        if let Some(r) = rest.strip_prefix("C-") {
+            seq.ctrl = true;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("M-") {
+            seq.alt = true;
+            rest = r;
+        } else {
+            break;
+        }
+    }
+
+    seq.key = match rest {
+        "Up" => UpKey,
+        "Down" => DownKey,
+        "Left" => LeftKey,
+        "Right" => RightKey,
+        "Delete" => DeleteKey,
+        "PageUp" => PageUpKey,
+        "PageDown" => PageDownKey,
+        "BackTab" | "S-Tab" => BackTabKey,
+        "Backspace" => Key(0x7f),
+        "Esc" => Key(0x1b),
+        "Space" => Key(b' '),
+        // Terminals send Tab and Enter as Ctrl-I and Ctrl-M
+        "Tab" => {
+            seq.ctrl = true;
+            Key(b'i')
+        }
+        "Enter" => {
+            seq.ctrl = true;
+            Key(b'm')
+        }
+        _ => {
+            let mut chars = rest.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_graphic() => Key(c as u8),
+                (Some(c), None) if !c.is_ascii() => Utf8Key(c),
+                _ => return Err(format!("Unknown key {:?}", notation)),
+            }
+        }
+    };
+
+    if seq.ctrl {
+        seq.key = match seq.key {
+            Key(b) if b.is_ascii_alphabetic() => Key(b.to_ascii_lowercase()),
+            Key(b' ') | Key(b'\\') | Key(b']') | Key(b'~') | Key(b'?') => seq.key,
+            UpKey | DownKey | LeftKey | RightKey => seq.key,
+            _ => return Err(format!("Key {:?} cannot be sent by terminals", notation)),
+        };
+    }
+
+    Ok(seq)
+}
+
+// Parses a key sequence such as "C-x C-s" or "C-k b". Keys are separated by spaces.
+pub fn parse_keys(notation: &str) -> Result<Vec<InputSeq>, String> {
+    let keys = notation.split_whitespace().map(parse_key).collect::<Result<Vec<_>, _>>()?;
+    if keys.is_empty() {
+        return Err("Empty key sequence".to_string());
+    }
+    Ok(keys)
+}
+
+// Inverse of `parse_key`
+pub fn key_name(seq: &InputSeq) -> String {
+    use KeySeq::*;
+
+    let mut ctrl = seq.ctrl;
+    let name = match (&seq.key, seq.ctrl) {
+        (Key(b'i'), true) => {
+            ctrl = false;
+            "Tab".to_string()
+        }
+        (Key(b'm'), true) => {
+            ctrl = false;
+            "Enter".to_string()
+        }
+        (Key(b' '), _) => "Space".to_string(),
+        (Key(0x7f), _) => "Backspace".to_string(),
+        (Key(0x1b), _) => "Esc".to_string(),
+        (Key(b), _) if b.is_ascii_control() => format!("\\x{:x}", b),
+        (Key(b), _) => (*b as char).to_string(),
+        (Utf8Key(c), _) => c.to_string(),
+        (UpKey, _) => "Up".to_string(),
+        (DownKey, _) => "Down".to_string(),
+        (LeftKey, _) => "Left".to_string(),
+        (RightKey, _) => "Right".to_string(),
+        (DeleteKey, _) => "Delete".to_string(),
+        (PageUpKey, _) => "PageUp".to_string(),
+        (PageDownKey, _) => "PageDown".to_string(),
+        (BackTabKey, _) => "BackTab".to_string(),
+        (key, _) => key.to_string(),
+    };
+
+    let mut s = String::new();
+    if ctrl {
+        s.push_str("C-");
+    }
+    if seq.alt {
+        s.push_str("M-");
+    }
+    s.push_str(&name);
+    s
+}
+
+pub fn keys_name(keys: &[InputSeq]) -> String {
+    keys.iter().map(key_name).collect::<Vec<_>>().join(" ")
+}
+
+fn is_strict_prefix(prefix: &[InputSeq], keys: &[InputSeq]) -> bool {
+    prefix.len() < keys.len() && keys.starts_with(prefix)
+}
+
+pub enum Lookup {
+    Command(Command),
+    // The keys are a prefix of some key sequences. More keys are needed.
+    Prefix,
+    Unbound,
+}
+
+// A binding written in the configuration file. It is validated when building `Keymap` so that
+// a typo in one binding does not prevent the editor from starting.
+#[derive(Clone)]
+pub struct KeyBinding {
+    pub context: KeyContext,
+    pub keys: String,
+    // `None` removes the default binding of the keys
+    pub command: Option<String>,
+}
+
+type Bindings = HashMap<Vec<InputSeq>, Command>;
+
+pub struct Keymap {
+    editor: Bindings,
+    prompt: Bindings,
+}
+
+impl Keymap {
+    pub fn new(profile: KeyProfile) -> Self {
+        let mut keymap = Self {
+            editor: HashMap::new(),
+            prompt: HashMap::new(),
+        };
+        let mut bind_all = |ctx, keys: &[(&str, Command)]| {
+            for (k, c) in keys {
+                keymap.bind(ctx, parse_keys(k).expect("invalid default key binding"), *c);
+            }
+        };
+        bind_all(KeyContext::Editor, EDITOR_KEYS);
+        bind_all(KeyContext::Prompt, PROMPT_KEYS);
+        if profile == KeyProfile::Emacs {
+            bind_all(KeyContext::Editor, EMACS_KEYS);
+        }
+        keymap
+    }
+
+    // Bindings conflicting with the new one are overridden. A key sequence conflicts with its
+    // prefix since the prefix would be run before the rest is typed.
+    fn bind(&mut self, ctx: KeyContext, keys: Vec<InputSeq>, cmd: Command) {
+        let map = self.map_mut(ctx);
+        map.retain(|k, _| !is_strict_prefix(k, &keys) && !is_strict_prefix(&keys, k));
+        map.insert(keys, cmd);
+    }
+
+    // Builds the keymap of the profile overridden by `bindings`. Unknown or conflicting bindings
+    // are skipped and returned as problems to be reported to the user.
+    pub fn with_bindings(profile: KeyProfile, bindings: &[KeyBinding]) -> (Self, Vec<String>) {
+        let mut keymap = Self::new(profile);
+        let mut problems = vec![];
+        let mut overrides: Vec<(KeyContext, Vec<InputSeq>, Option<Command>, &str)> = vec![];
+
+        for binding in bindings {
+            let keys = match parse_keys(&binding.keys) {
+                Ok(keys) => keys,
+                Err(msg) => {
+                    problems.push(msg);
+                    continue;
+                }
+            };
+
+            let cmd = match &binding.command {
+                None => None,
+                Some(name) => match Command::from_name(name) {
+                    Some(cmd) if binding.context.accepts(cmd) => Some(cmd),
+                    Some(cmd) => {
+                        problems.push(format!("`{}` is not available in {}", cmd, binding.context));
+                        continue;
+                    }
+                    None => {
+                        problems.push(format!("Unknown command `{}` for {:?}", name, binding.keys));
+                        continue;
+                    }
+                },
+            };
+
+            // The same key can be written in several ways (e.g. "Tab" and "C-i")
+            let conflict = overrides.iter().position(|(ctx, k, c, _)| {
+                *ctx == binding.context
+                    && (*k == keys && *c != cmd
+                        || c.is_some() && cmd.is_some() && (is_strict_prefix(k, &keys) || is_strict_prefix(&keys, k)))
+            });
+            if let Some(idx) = conflict {
+                let (_, _, _, other) = overrides.remove(idx);
+                problems.push(format!(
+                    "{:?} and {:?} conflict in {}",
+                    other, binding.keys, binding.context,
+                ));
+                continue;
+            }
+
+            overrides.push((binding.context, keys, cmd, &binding.keys));
+        }
+
+        for (ctx, keys, cmd, _) in overrides {
+            match cmd {
+                Some(cmd) => keymap.bind(ctx, keys, cmd),
+                None => {
+                    keymap.map_mut(ctx).remove(&keys);
+                }
+            }
+        }
+
+        (keymap, problems)
+    }
+
+    fn map(&self, ctx: KeyContext) -> &Bindings {
+        match ctx {
+            KeyContext::Editor => &self.editor,
+            KeyContext::Prompt => &self.prompt,
+        }
+    }
+
+    fn map_mut(&mut self, ctx: KeyContext) -> &mut Bindings {
+        match ctx {
+            KeyContext::Editor => &mut self.editor,
+            KeyContext::Prompt => &mut self.prompt,
+        }
+    }
+
+    pub fn lookup(&self, ctx: KeyContext, keys: &[InputSeq]) -> Lookup {
+        let map = self.map(ctx);
+        if let Some(cmd) = map.get(keys) {
+            Lookup::Command(*cmd)
+        } else if map.keys().any(|k| is_strict_prefix(keys, k)) {
+            Lookup::Prefix
+        } else {
+            Lookup::Unbound
+        }
+    }
+
+    // True when the single key is bound to the command. Used for keys which work in the middle
+    // of a key sequence such as cancel.
+    pub fn is_bound_to(&self, ctx: KeyContext, key: &InputSeq, cmd: Command) -> bool {
+        self.map(ctx)
+            .iter()
+            .any(|(k, c)| *c == cmd && k.len() == 1 && k[0] == *key)
+    }
+
+    // Keys which can follow the prefix with the commands they run, sorted by keys. Key sequences
+    // sharing the next key are shown as one prefix entry (`None`).
+    pub fn continuations(&self, ctx: KeyContext, prefix: &[InputSeq]) -> Vec<(String, Option<Command>)> {
+        let mut entries: Vec<_> = self
+            .map(ctx)
+            .iter()
+            .filter(|(k, _)| is_strict_prefix(prefix, k))
+            .map(|(k, c)| {
+                let next = &k[prefix.len()];
+                let cmd = if k.len() == prefix.len() + 1 { Some(*c) } else { None };
+                (key_name(next), cmd)
+            })
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.dedup_by(|a, b| a.0 == b.0);
+        entries
+    }
+
+    // Name of a key bound to the command, used in messages to show how to invoke it
+    pub fn key_for(&self, ctx: KeyContext, cmd: Command) -> Option<String> {
+        self.map(ctx)
+            .iter()
+            .filter(|(_, c)| **c == cmd)
+            .map(|(keys, _)| keys_name(keys))
+            .min_by_key(|name| (name.len(), name.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binding(context: KeyContext, keys: &str, command: &str) -> KeyBinding {
+        KeyBinding {
+            context,
+            keys: keys.to_string(),
+            command: if command.is_empty() { None } else { Some(command.to_string()) },
+        }
+    }
+
+    // Command bound to the keys in the editor. A prefix of key sequences is `None`.
+    fn lookup(keymap: &Keymap, keys: &str) -> Option<Command> {
+        match keymap.lookup(KeyContext::Editor, &parse_keys(keys).unwrap()) {
+            Lookup::Command(cmd) => Some(cmd),
+            Lookup::Prefix | Lookup::Unbound => None,
+        }
+    }
+
+    #[test]
+    fn parse_key_with_modifiers() {
+        use KeySeq::*;
+        assert_eq!(parse_key("a"), Ok(InputSeq::new(Key(b'a'))));
+        assert_eq!(parse_key("C-s"), Ok(InputSeq::ctrl(Key(b's'))));
+        assert_eq!(parse_key("C-S"), Ok(InputSeq::ctrl(Key(b's'))));
+        let mut alt_up = InputSeq::new(UpKey);
+        alt_up.alt = true;
+        assert_eq!(parse_key("M-Up"), Ok(alt_up));
+        let mut ctrl_alt_x = InputSeq::ctrl(Key(b'x'));
+        ctrl_alt_x.alt = true;
+        assert_eq!(parse_key("C-M-x"), Ok(ctrl_alt_x.clone()));
+        assert_eq!(parse_key("M-C-x"), Ok(ctrl_alt_x));
+        // "C-" alone is not a modifier but an unknown key
+        assert_eq!(parse_key("C-"), Err("Unknown key \"C-\"".to_string()));
+    }
+
+    #[test]
+    fn parse_key_names() {
+        use KeySeq::*;
+        assert_eq!(parse_key("Tab"), Ok(InputSeq::ctrl(Key(b'i'))));
+        assert_eq!(parse_key("Enter"), Ok(InputSeq::ctrl(Key(b'm'))));
+        assert_eq!(parse_key("Space"), Ok(InputSeq::new(Key(b' '))));
+        assert_eq!(parse_key("Backspace"), Ok(InputSeq::new(Key(0x7f))));
+        assert_eq!(parse_key("Esc"), Ok(InputSeq::new(Key(0x1b))));
+        assert_eq!(parse_key("S-Tab"), Ok(InputSeq::new(BackTabKey)));
+        assert_eq!(parse_key("é"), Ok(InputSeq::new(Utf8Key('é'))));
+    }
+
+    #[test]
+    fn parse_key_rejects_unknown_and_unsendable_keys() {
+        assert_eq!(parse_key("Foo"), Err("Unknown key \"Foo\"".to_string()));
+        assert_eq!(parse_key(""), Err("Unknown key \"\"".to_string()));
+        assert_eq!(parse_key("C-1"), Err("Key \"C-1\" cannot be sent by terminals".to_string()));
+        assert!(parse_key("C-Delete").is_err());
+    }
+
+    #[test]
+    fn key_name_is_inverse_of_parse_key() {
+        for name in ["a", "C-s", "M-Up", "C-M-x", "Tab", "Enter", "Space", "Backspace", "Esc", "BackTab", "é"] {
+            assert_eq!(key_name(&parse_key(name).unwrap()), name);
+        }
+        assert_eq!(key_name(&parse_key("C-i").unwrap()), "Tab");
+    }
+
+    #[test]
+    fn parse_key_sequences() {
+        let keys = parse_keys("C-x  C-s").unwrap();
+        assert_eq!(keys, vec![parse_key("C-x").unwrap(), parse_key("C-s").unwrap()]);
+        assert_eq!(keys_name(&keys), "C-x C-s");
+        assert_eq!(parse_keys(" "), Err("Empty key sequence".to_string()));
+        assert!(parse_keys("C-x Foo").is_err());
+    }
+
+    #[test]
+    fn with_bindings_overrides_defaults() {
+        let bindings = [
+            binding(KeyContext::Editor, "C-s", ""),
+            binding(KeyContext::Editor, "M-s", "save"),
+            binding(KeyContext::Prompt, "C-j", "accept"),
+        ];
+        let (keymap, problems) = Keymap::with_bindings(KeyProfile::Default, &bindings);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(keymap.key_for(KeyContext::Editor, Command::Save), Some("M-s".to_string()));
+        assert_eq!(keymap.key_for(KeyContext::Prompt, Command::Accept), Some("C-j".to_string()));
+        assert_eq!(keymap.key_for(KeyContext::Editor, Command::SplitHorizontal), Some("C-x 2".to_string()));
+    }
+
+    #[test]
+    fn with_bindings_reports_problems() {
+        let bindings = [
+            binding(KeyContext::Editor, "C-1", "save"),
+            binding(KeyContext::Editor, "C-s", "frobnicate"),
+            binding(KeyContext::Editor, "C-s", "accept"),
+            binding(KeyContext::Editor, "Tab", "save"),
+            binding(KeyContext::Editor, "C-i", "quit"),
+        ];
+        let (keymap, problems) = Keymap::with_bindings(KeyProfile::Default, &bindings);
+        assert_eq!(
+            problems,
+            vec![
+                "Key \"C-1\" cannot be sent by terminals".to_string(),
+                "Unknown command `frobnicate` for \"C-s\"".to_string(),
+                "`accept` is not available in editor".to_string(),
+                "\"Tab\" and \"C-i\" conflict in editor".to_string(),
+            ],
+        );
+        // Skipped bindings leave the defaults
+        assert_eq!(keymap.key_for(KeyContext::Editor, Command::Save), Some("C-s".to_string()));
+        assert_eq!(keymap.key_for(KeyContext::Editor, Command::Indent), Some("Tab".to_string()));
+    }
+
+    #[test]
+    fn with_bindings_replaces_conflicting_prefixes() {
+        let bindings = [binding(KeyContext::Editor, "C-x", "save")];
+        let (keymap, problems) = Keymap::with_bindings(KeyProfile::Default, &bindings);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(lookup(&keymap, "C-x"), Some(Command::Save));
+        assert_eq!(keymap.key_for(KeyContext::Editor, Command::SplitHorizontal), Some("M-s".to_string()));
+
+        let bindings = [
+            binding(KeyContext::Editor, "C-t", "save"),
+            binding(KeyContext::Editor, "C-t C-t", "quit"),
+        ];
+        let (_, problems) = Keymap::with_bindings(KeyProfile::Default, &bindings);
+        assert_eq!(problems, vec!["\"C-t\" and \"C-t C-t\" conflict in editor".to_string()]);
+    }
+
+    #[test]
+    fn lookup_prefixes_of_sequences() {
+        let keymap = Keymap::new(KeyProfile::Default);
+        let keys = parse_keys("C-x 2").unwrap();
+        assert!(matches!(keymap.lookup(KeyContext::Editor, &keys[..1]), Lookup::Prefix));
+        assert!(matches!(keymap.lookup(KeyContext::Editor, &keys[1..]), Lookup::Unbound));
+        assert_eq!(lookup(&keymap, "C-x 2"), Some(Command::SplitHorizontal));
+        assert_eq!(lookup(&keymap, "C-x z"), None);
+    }
+
+    #[test]
+    fn emacs_profile_adds_bindings() {
+        let keymap = Keymap::new(KeyProfile::Emacs);
+        assert_eq!(lookup(&keymap, "C-a"), Some(Command::LineStart));
+        assert_eq!(lookup(&keymap, "C-x C-s"), Some(Command::Save));
+        assert_eq!(lookup(&Keymap::new(KeyProfile::Default), "C-a"), None);
+    }
+}
@@ -1,9 +1,18 @@
 
+mod brackets;
+mod config;
 mod edit_diff;
 mod editor;
 mod error;
+mod ex;
+mod filetype;
+mod filter;
+mod grid;
+mod gutter;
 mod history;
 mod input;
+mod keymap;
+mod macros;
 mod prompt;
 mod row;
 mod screen;
@@ -11,7 +20,10 @@ mod signal;
 mod status_bar;
 mod term_color;
 mod text_buffer;
+mod vi;
+mod window;
 
+pub use config::Config;
 pub use editor::Editor;
 pub use error::{Result};
 pub use input::{StdinRawMode};
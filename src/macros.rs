@@ -0,0 +1,127 @@
+use crate::error::{Error, Result};
+use crate::input::{InputSeq, KeySeq};
+use crate::keymap;
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Wraps the input of the editor to record keystrokes and to feed recorded keystrokes back.
+// Prompts read keys from this input as well so that text typed in prompts is also recorded.
+pub struct MacroInput<I: Iterator<Item = Result<InputSeq>>> {
+    input: I,
+    recording: Option<Vec<InputSeq>>,
+    playback: VecDeque<InputSeq>,
+}
+
+impl<I: Iterator<Item = Result<InputSeq>>> MacroInput<I> {
+    pub fn new(input: I) -> Self {
+        Self {
+            input,
+            recording: None,
+            playback: VecDeque::new(),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn start_recording(&mut self) {
+        self.recording = Some(vec![]);
+    }
+
+    // Returns the recorded keys. The last `trailing` keys are dropped since they are the keys
+    // which stopped the recording.
+    pub fn stop_recording(&mut self, trailing: usize) -> Option<Vec<InputSeq>> {
+        let mut keys = self.recording.take()?;
+        keys.truncate(keys.len().saturating_sub(trailing));
+        Some(keys)
+    }
+
+    pub fn play(&mut self, keys: &[InputSeq]) {
+        self.playback.extend(keys.iter().cloned());
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.playback.is_empty()
+    }
+
+    pub fn cancel_playback(&mut self) {
+        self.playback.clear();
+    }
+}
+
+impl<I: Iterator<Item = Result<InputSeq>>> Iterator for MacroInput<I> {
+    type Item = Result<InputSeq>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(seq) = self.playback.pop_front() {
+            return Some(Ok(seq));
+        }
+        let item = self.input.next();
+        if let (Some(keys), Some(Ok(seq))) = (&mut self.recording, &item) {
+            // Ticks and mouse positions are not keystrokes
+            if !matches!(seq.key, KeySeq::Unidentified | KeySeq::Cursor(..)) {
+                keys.push(seq.clone());
+            }
+        }
+        item
+    }
+}
+
+// $XDG_DATA_HOME/berry/macros.toml, falling back to ~/.local/share/berry/macros.toml
+pub fn path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local").join("share"),
+    };
+    Some(dir.join("berry").join("macros.toml"))
+}
+
+// Named macros are stored as key notation such as `name = "C-a M-f C-k"`
+fn load_table(path: &Path) -> Result<toml::value::Table> {
+    if !path.exists() {
+        return Ok(toml::value::Table::new());
+    }
+    let source = fs::read_to_string(path)?;
+    match source.parse::<toml::Value>() {
+        Ok(toml::Value::Table(table)) => Ok(table),
+        Ok(_) => Err(Error::InvalidMacroFile(path.to_path_buf(), "Top level must be a table".to_string())),
+        Err(err) => Err(Error::InvalidMacroFile(path.to_path_buf(), err.to_string())),
+    }
+}
+
+fn no_path() -> Error {
+    Error::InvalidMacroFile(PathBuf::from("macros.toml"), "$HOME is not set".to_string())
+}
+
+pub fn save(name: &str, keys: &[InputSeq]) -> Result<PathBuf> {
+    let path = path().ok_or_else(no_path)?;
+    let mut table = load_table(&path)?;
+    table.insert(name.to_string(), toml::Value::String(keymap::keys_name(keys)));
+    let source = toml::to_string(&toml::Value::Table(table))
+        .map_err(|err| Error::InvalidMacroFile(path.clone(), err.to_string()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, source)?;
+    Ok(path)
+}
+
+// `None` means no macro is saved with the name
+pub fn load(name: &str) -> Result<Option<Vec<InputSeq>>> {
+    let path = path().ok_or_else(no_path)?;
+    let table = load_table(&path)?;
+    let notation = match table.get(name) {
+        Some(toml::Value::String(s)) => s,
+        Some(_) => {
+            let msg = format!("Macro {:?} must be a string of keys", name);
+            return Err(Error::InvalidMacroFile(path, msg));
+        }
+        None => return Ok(None),
+    };
+    let keys = keymap::parse_keys(notation)
+        .map_err(|msg| Error::InvalidMacroFile(path, format!("Macro {:?}: {}", name, msg)))?;
+    Ok(Some(keys))
+}
@@ -1,5 +1,5 @@
 use getopts::Options;
-use berry::{self as berry, Editor, StdinRawMode, HELP, VERSION};
+use berry::{self as berry, Config, Editor, StdinRawMode, HELP, VERSION};
 use std::env;
 use std::io;
 use std::process::exit;
@@ -12,6 +12,12 @@ Berry is a tiny UTF-8 text editor on terminals for Unix-like systems.
 Specify file paths to edit as a command argument or run without argument to
 start to write a new text.
 Help can show up with key mapping Ctrl-?.
+Settings are read from $XDG_CONFIG_HOME/berry/config.toml (~/.config/berry/config.toml
+by default). Key bindings can be changed in its [keys.editor] and [keys.prompt]
+tables, e.g. \"C-x\" = \"save\". Emacs-style keys (Ctrl-A/E/F/B/N/P, Alt-F/B, Ctrl-K,
+Ctrl-Y) are enabled by key_profile = \"emacs\", and Vi-style modal editing by
+key_profile = \"vi\". Named keyboard macros are saved to
+$XDG_DATA_HOME/berry/macros.toml (~/.local/share/berry/macros.toml by default).
 
 Usage:
     {prog} [options] [FILES...]
@@ -25,8 +31,11 @@ Mappings:
 }
 
 fn edit(files: Vec<String>) -> berry::Result<()> {
-    let input = StdinRawMode::new()?.input_keys();
-    Editor::open(input, io::stdout(), None, &files)?.edit()
+    let config = Config::load()?;
+    let input = StdinRawMode::new()?.input_keys()?;
+    let mut editor = Editor::open(input, io::stdout(), None, &files)?;
+    editor.set_config(config);
+    editor.edit()
 }
 
 fn main() {
@@ -1,10 +1,12 @@
 use crate::error::Result;
 use crate::input::{InputSeq, KeySeq};
+use crate::keymap::{Command, KeyContext, Keymap, Lookup};
 use crate::screen::Screen;
-use crate::status_bar::StatusBar;
+use crate::status_bar::text_width;
 use crate::text_buffer::TextBuffer;
 
 use std::io::Write;
+use std::mem;
 
 #[derive(PartialEq)]
 pub enum PromptResult {
@@ -24,6 +26,11 @@ pub trait Action: Sized {
         Ok(false)
     }
 
+    // Called with the `complete` command. Returns the completed input.
+    fn complete(&mut self, _input: &str) -> Option<String> {
+        None
+    }
+
     fn on_end<W: Write>(
         self,
         _prompt: &mut Prompt<'_, W>,
@@ -42,16 +49,16 @@ impl Action for NoAction {
 struct PromptTemplate<'a> {
     prefix: &'a str,
     suffix: &'a str,
-    prefix_chars: usize,
+    prefix_width: usize,
 }
 
 impl<'a> PromptTemplate<'a> {
     fn new(prefix: &'a str, suffix: &'a str) -> Self {
-        let prefix_chars = prefix.chars().count();
+        let prefix_width = text_width(prefix);
         Self {
             prefix,
             suffix,
-            prefix_chars,
+            prefix_width,
         }
     }
 
@@ -65,41 +72,41 @@ impl<'a> PromptTemplate<'a> {
     }
 
     fn cursor_col(&self, input: &str) -> usize {
-        self.prefix_chars + input.chars().count() + 1
+        self.prefix_width + text_width(input) + 1
     }
 }
 
 pub struct Prompt<'a, W: Write> {
     screen: &'a mut Screen<W>,
-    buf: &'a mut TextBuffer,
-    sb: &'a mut StatusBar,
+    bufs: &'a mut [TextBuffer],
+    keymap: &'a Keymap,
     empty_is_cancel: bool,
 }
 
 impl<'a, W: Write> Prompt<'a, W> {
-    pub fn new<'s: 'a, 'tb: 'a, 'h: 'a, 'sb: 'a>(
+    pub fn new<'s: 'a, 'tb: 'a, 'k: 'a>(
         screen: &'s mut Screen<W>,
-        buf: &'tb mut TextBuffer,
-        sb: &'sb mut StatusBar,
+        bufs: &'tb mut [TextBuffer],
+        keymap: &'k Keymap,
         empty_is_cancel: bool,
     ) -> Self {
-        Self { screen, buf, sb, empty_is_cancel }
+        Self {
+            screen,
+            bufs,
+            keymap,
+            empty_is_cancel,
+        }
     }
 
     fn render_screen(&mut self, input: &str, template: &PromptTemplate<'_>) -> Result<()> {
         self.screen.set_info_message(template.build(input));
-        self.sb.update_from_buf(self.buf);
-        self.screen.render(self.buf, self.sb)?;
+        self.screen.render(self.bufs)?;
 
-        let row = self.screen.rows() + 2;
         let col = template.cursor_col(input);
-        self.screen.force_set_cursor(row, col)?;
-
-        self.sb.redraw = false;
-        Ok(())
+        self.screen.set_prompt_cursor(col)
     }
 
-    pub fn run<A, S, I>(&mut self, prompt: S, mut input: I) -> Result<PromptResult>
+    pub fn run<A, S, I>(&mut self, prompt: S, input: I) -> Result<PromptResult>
     where
         A: Action,
         S: AsRef<str>,
@@ -108,42 +115,61 @@ impl<'a, W: Write> Prompt<'a, W> {
         let mut action = A::new(self);
         let mut buf = String::new();
         let mut canceled = false;
+        let mut pending_keys = vec![];
 
         let template = {
-            let mut it = prompt.as_ref().splitn(2, "{}");
-            let prefix = it.next().unwrap();
-            let suffix = it.next().unwrap();
+            let (prefix, suffix) = prompt.as_ref().split_once("{}").unwrap();
             PromptTemplate::new(prefix, suffix)
         };
 
-        self.render_screen("", &template);
+        self.screen.set_prompting(true);
+        self.render_screen("", &template)?;
 
-        while let Some(seq) = input.next() {
+        for seq in input {
             use KeySeq::*;
 
-            if self.screen.maybe_resize(&mut input)? {
-                self.screen.set_dirty_start(self.screen.rowoff);
-                self.sb.redraw = true;
+            if self.screen.maybe_resize()? {
                 self.render_screen(&buf, &template)?;
                 continue;
             }
 
             let seq = seq?;
             let prev_len = buf.len();
+            let mut completed = false;
 
-            match (&seq.key, &seq.ctrl) {
-                (Unidentified, ..) => continue,
-                (Key(b'h'), true) | (Key(0x7f), ..) | (DeleteKey, ..) => {
-                    if !buf.is_empty() {
-                        buf.pop();
-                    }
+            if seq.key == Unidentified {
+                continue;
+            }
+
+            if !pending_keys.is_empty() && self.keymap.is_bound_to(KeyContext::Prompt, &seq, Command::Cancel) {
+                pending_keys.clear();
+                continue;
+            }
+
+            pending_keys.push(seq.clone());
+            let cmd = match self.keymap.lookup(KeyContext::Prompt, &pending_keys) {
+                Lookup::Prefix => continue,
+                Lookup::Command(cmd) => Some(cmd),
+                Lookup::Unbound => None,
+            };
+            let keys = mem::take(&mut pending_keys);
+
+            match cmd {
+                Some(Command::DeleteChar) => {
+                    buf.pop();
                 }
-                (Key(b'g'), true) | (Key(b'q'), true) | (Key(0x1b), ..) => {
+                Some(Command::Cancel) => {
                     canceled = true;
                     break;
                 }
-                (Key(b'\r'), ..) | (Key(b'm'), true) => break,
-                (Key(b'w'), true) => {
+                Some(Command::Accept) => break,
+                Some(Command::Complete) => {
+                    if let Some(input) = action.complete(&buf) {
+                        buf = input;
+                        completed = true;
+                    }
+                }
+                Some(Command::DeleteWordBackward) => {
                     while let Some(current) = buf.pop() {
                         if let Some(next) = buf.chars().last() {
                             let next_is_not_char = next.is_ascii_punctuation() || next.is_ascii_whitespace();
@@ -154,24 +180,29 @@ impl<'a, W: Write> Prompt<'a, W> {
                         }
                     }
                 }
-                (Key(b), false) => buf.push(*b as char),
-                (Utf8Key(c), false) => buf.push(*c),
-                _ => {}
+                Some(_) => {}
+                None if keys.len() > 1 => {}
+                None => match &seq {
+                    InputSeq { ctrl: true, .. } | InputSeq { alt: true, .. } => {}
+                    InputSeq { key: Key(b), .. } if !b.is_ascii_control() => buf.push(*b as char),
+                    InputSeq { key: Utf8Key(c), .. } => buf.push(*c),
+                    _ => {}
+                },
             }
 
             let should_render = action.on_seq(self, buf.as_str(), seq)?;
 
-            if should_render || prev_len != buf.len() {
+            if should_render || completed || prev_len != buf.len() {
                 self.render_screen(&buf, &template)?;
             }
         }
 
+        self.screen.set_prompting(false);
         let result = if canceled || self.empty_is_cancel && buf.is_empty() {
             self.screen.set_info_message("Canceled");
             PromptResult::Canceled
         } else {
             self.screen.unset_message();
-            self.sb.redraw = true;
             PromptResult::Input(buf)
         };
         
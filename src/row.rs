@@ -2,34 +2,44 @@ use crate::error::{Error, Result};
 use std::ops;
 use unicode_width::UnicodeWidthChar;
 
-const TAB_STOP: usize = 4;
+pub const TAB_STOP: usize = 4;
 
 #[derive(Default)]
 pub struct Row {
     buf: String,
     render: String,
     indices: Vec<usize>,
+    tab_stop: usize,
 }
 
 impl Row {
-    pub fn empty() -> Row {
+    pub fn empty(tab_stop: usize) -> Row {
         Row {
             buf: "".to_string(),
             render: "".to_string(),
             indices: Vec::with_capacity(0),
+            tab_stop,
         }
     }
 
-    pub fn new<S: Into<String>>(line: S) -> Result<Row> {
+    pub fn new<S: Into<String>>(line: S, tab_stop: usize) -> Result<Row> {
         let mut row = Row {
             buf: line.into(),
             render: "".to_string(),
             indices: Vec::with_capacity(0),
+            tab_stop,
         };
         row.update_render()?;
         Ok(row)
     }
 
+    pub fn set_tab_stop(&mut self, tab_stop: usize) {
+        if self.tab_stop != tab_stop {
+            self.tab_stop = tab_stop;
+            self.update_render().unwrap();
+        }
+    }
+
     pub fn len(&self) -> usize {
         if self.indices.is_empty() {
             self.buf.len()
@@ -76,7 +86,7 @@ impl Row {
                 loop {
                     self.render.push(' ');
                     index += 1;
-                    if index % TAB_STOP == 0 {
+                    if index % self.tab_stop == 0 {
                         break;
                     }
                 }
@@ -105,13 +115,68 @@ impl Row {
     pub fn rx_from_cx(&self, cx: usize) -> usize {
         self[..cx].chars().fold(0, |rx, ch| {
             if ch == '\t' {
-                rx + TAB_STOP - (rx % TAB_STOP)
+                rx + self.tab_stop - (rx % self.tab_stop)
             } else {
                 rx + ch.width_cjk().unwrap()
             }
         })
     }
 
+    // Inverse of `rx_from_cx`. A display column in the middle of a tab or a wide character, or past
+    // the end of line, gives the character containing it or the end of line.
+    pub fn cx_from_rx(&self, rx: usize) -> usize {
+        let mut cur = 0;
+        for (cx, ch) in self.buffer().chars().enumerate() {
+            cur += if ch == '\t' {
+                self.tab_stop - (cur % self.tab_stop)
+            } else {
+                ch.width_cjk().unwrap_or(1)
+            };
+            if cur > rx {
+                return cx;
+            }
+        }
+        self.len()
+    }
+
+    // Splits the line into visual lines which fit in `width` display columns. Lines are broken
+    // after whitespace where possible, otherwise between characters. A wide character never
+    // straddles two visual lines. Returns ranges of character indices.
+    pub fn wrap_segments(&self, width: usize) -> Vec<(usize, usize)> {
+        let mut segments = vec![];
+        let mut start = 0;
+        let mut start_rx = 0;
+        let mut brk = None;
+        let mut rx = 0;
+
+        for (i, c) in self.buf.chars().enumerate() {
+            let w = if c == '\t' {
+                self.tab_stop - rx % self.tab_stop
+            } else {
+                c.width_cjk().unwrap_or(1)
+            };
+
+            if rx + w - start_rx > width && i > start {
+                let at = match brk {
+                    Some(b) if b > start => b,
+                    _ => i,
+                };
+                segments.push((start, at));
+                start = at;
+                start_rx = self.rx_from_cx(at);
+                brk = None;
+            }
+
+            rx += w;
+            if c == ' ' || c == '\t' {
+                brk = Some(i + 1);
+            }
+        }
+
+        segments.push((start, self.len()));
+        segments
+    }
+
     pub fn insert_char(&mut self, at: usize, c: char) {
         if self.len() <= at {
             self.buf.push(c);
@@ -199,3 +264,43 @@ impl ops::Index<ops::RangeToInclusive<usize>> for Row {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(line: &str, width: usize) -> Vec<(usize, usize)> {
+        Row::new(line, TAB_STOP).unwrap().wrap_segments(width)
+    }
+
+    #[test]
+    fn wrap_short_and_empty_lines() {
+        assert_eq!(segments("", 5), vec![(0, 0)]);
+        assert_eq!(segments("abcde", 5), vec![(0, 5)]);
+    }
+
+    #[test]
+    fn wrap_after_whitespace() {
+        assert_eq!(segments("foo bar baz", 8), vec![(0, 8), (8, 11)]);
+        assert_eq!(segments("foo bar baz", 5), vec![(0, 4), (4, 8), (8, 11)]);
+    }
+
+    #[test]
+    fn wrap_long_word_between_characters() {
+        assert_eq!(segments("abcdefghij", 4), vec![(0, 4), (4, 8), (8, 10)]);
+        assert_eq!(segments("ab cdefghij", 4), vec![(0, 3), (3, 7), (7, 11)]);
+    }
+
+    #[test]
+    fn wrap_wide_characters_without_splitting_them() {
+        assert_eq!(segments("あいう", 5), vec![(0, 2), (2, 3)]);
+        assert_eq!(segments("aあいう", 4), vec![(0, 2), (2, 4)]);
+    }
+
+    #[test]
+    fn wrap_tabs_by_their_expanded_width() {
+        // The tab takes 3 columns after 'a' so that "bcdef" does not fit after it
+        assert_eq!(segments("a\tbcdef", 6), vec![(0, 2), (2, 7)]);
+        assert_eq!(segments("a\tbc", 6), vec![(0, 4)]);
+    }
+}
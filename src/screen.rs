@@ -1,21 +1,59 @@
+use crate::brackets::BracketMatch;
+use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::input::{InputSeq, KeySeq};
+use crate::filetype::FileType;
+use crate::grid::Grid;
+use crate::gutter::{Gutter, LineNumbers};
 use crate::row::Row;
 use crate::signal::SigwinchWatcher;
-use crate::status_bar::StatusBar;
-use crate::term_color::{Color};
+use crate::status_bar::{text_width, truncate_to_width, StatusBar};
+use crate::term_color::{Color, Theme};
 use crate::text_buffer::TextBuffer;
+use crate::vi::Mode;
+use crate::window::{Layout, Rect, SplitDir, Window};
 
 use std::cmp;
 use std::io::Write;
-use std::time::SystemTime;
+use std::mem;
+use std::time::{Duration, SystemTime};
 use unicode_width::UnicodeWidthChar;
 
-use crossterm::{execute, cursor, terminal};
+use crossterm::{execute, queue, cursor, terminal};
 
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const HELP: &str = "\
-    Ctrl-?              : Show this help";
+    Ctrl-?              : Show this help
+    Ctrl-Space          : Start or cancel selection
+    Alt-N               : Add cursor at next match of word under cursor
+    Alt-UP/Alt-DOWN     : Add cursor on line above/below
+    Alt-L               : Split selection into one cursor per line
+    ESC                 : Clear extra cursors and selection
+    Ctrl-LEFT/Ctrl-RIGHT: Move to previous/next word
+    Ctrl-W/Alt-D        : Delete word before/after cursor
+    Ctrl-X D/Alt-K      : Duplicate/delete selected lines or the line at cursor
+    Ctrl-Alt-UP/DOWN    : Move selected lines or the line at cursor up/down
+    Alt-J               : Join lines
+    PGUP/PGDN           : Scroll up/down by a page (Alt- for a half page)
+    Ctrl-UP/Ctrl-DOWN   : Scroll the view up/down without moving cursor
+    Ctrl-L              : Put the cursor line at the middle, top or bottom of window
+    Ctrl-O/Ctrl-X Ctrl-O: Open a new line below/above
+    Alt-S/Alt-V         : Split window horizontally/vertically
+    Alt-C               : Close window
+    Alt-O/Alt-P         : Move to next/previous window
+    Ctrl-X 2/3/0/O      : Split horizontally/vertically, close or move to next window
+    Ctrl-G              : Cancel a partially typed key sequence
+    Ctrl-X ( / ) / E    : Start/stop recording a keyboard macro, or play it
+    Ctrl-X Ctrl-K R/S/L : Play macro repeatedly, save it with a name or load a saved one
+    Alt-=/Alt--         : Grow/shrink window
+    Alt-G               : Cycle line numbers (off/absolute/relative/hybrid)
+    Alt-W               : Toggle soft-wrap of long lines
+    Tab/Shift-Tab       : Indent/dedent at cursor or over selection
+    Alt-T               : Toggle indenting with spaces or tabs
+    Alt-R               : Reload configuration file
+    Alt-X               : Command line (:w, :saveas, :q, :e, :b, :42, :set, :s/a/b/g, :%!sort)
+    Alt-|               : Filter selected lines or whole buffer through a shell command
+    Alt-M               : Jump to the bracket matching the one at cursor
+    Alt-;               : Comment or uncomment selected lines or the line at cursor";
 
 #[derive(PartialEq)]
 enum StatusMessageKind {
@@ -44,9 +82,8 @@ fn get_window_size() -> Result<(u16, u16)>
     terminal::size().map_err(|_| Error::UnknownWindowSize)
 }
 
-fn too_small_window(width: u16, height: u16) -> bool {
-    width < 1 || height < 3    
-}
+// Text shown instead of the windows when some of them have no room for text
+const TOO_SMALL: &str = "Window too small";
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 enum DrawMessage {
@@ -75,336 +112,887 @@ impl DrawMessage {
     }
 }
 
+// Position of a bracket highlighted in the focused window
+type BracketMark = ((usize, usize), Color);
+
+// Focused window, its buffer, and the cursor, version and file type of the buffer
+type BracketKey = (usize, usize, (usize, usize), usize, FileType);
+
+// Ranges of render columns in a row which are drawn with extra colors
+struct RowHighlight {
+    cursors: Vec<(usize, usize)>,
+    selection: Option<(usize, usize)>,
+    brackets: Vec<(usize, usize, Color)>,
+}
+
+impl RowHighlight {
+    // Secondary cursors and the selection belong to the focused window so other windows on the
+    // same buffer don't show them
+    fn new(row: &Row, file_row: usize, text_buf: &TextBuffer, focused: bool, brackets: &[BracketMark]) -> Self {
+        if !focused {
+            return Self {
+                cursors: vec![],
+                selection: None,
+                brackets: vec![],
+            };
+        }
+
+        let cursors = text_buf
+            .secondary_cursors()
+            .iter()
+            .filter(|&&(_, cy)| cy == file_row)
+            .map(|&(cx, _)| {
+                let rx = row.rx_from_cx(cx);
+                let next = if cx < row.len() { row.rx_from_cx(cx + 1) } else { rx + 1 };
+                (rx, next)
+            })
+            .collect();
+
+        let selection = text_buf.selection().and_then(|((sx, sy), (ex, ey))| {
+            if file_row < sy || ey < file_row {
+                return None;
+            }
+            let start = if file_row == sy { row.rx_from_cx(sx) } else { 0 };
+            let end = if file_row == ey {
+                row.rx_from_cx(cmp::min(ex, row.len()))
+            } else {
+                row.rx_from_cx(row.len()) + 1
+            };
+            Some((start, end))
+        });
+
+        let brackets = brackets
+            .iter()
+            .filter(|&&((_, y), _)| y == file_row)
+            .map(|&((x, _), color)| (row.rx_from_cx(x), row.rx_from_cx(x + 1), color))
+            .collect();
+
+        Self {
+            cursors,
+            selection,
+            brackets,
+        }
+    }
+
+    fn color_at(&self, rx: usize) -> Color {
+        if self.cursors.iter().any(|&(s, e)| s <= rx && rx < e) {
+            Color::Cursor
+        } else if let Some(&(_, _, color)) = self.brackets.iter().find(|&&(s, e, _)| s <= rx && rx < e) {
+            color
+        } else if self.selection.map(|(s, e)| s <= rx && rx < e).unwrap_or(false) {
+            Color::Selection
+        } else {
+            Color::Reset
+        }
+    }
+}
+
 pub struct Screen<W: Write> {
     output: W,
-    rx: usize,
     num_cols: usize,
+    // Height of the whole terminal including the status bars and the message bar
     num_rows: usize,
     message: Option<StatusMessage>,
     draw_message: DrawMessage,
-dirty_start: Option<usize>,
     sigwinch: SigwinchWatcher,
     pub cursor_moved: bool,
-    pub rowoff: usize,
-    pub coloff: usize,
+    windows: Vec<Window>,
+    layout: Layout,
+    active: usize,
+    // Area which windows were last arranged in
+    area: Rect,
+    gutter: Gutter,
+    theme: Theme,
+    message_timeout: Option<Duration>,
+    // Lines of the panel listing keys which can follow a pending key sequence
+    hints: Vec<String>,
+    draw_hints: bool,
+    // Modal editing mode shown in the status bar of the focused window
+    mode: Option<Mode>,
+    // Bracket at the cursor and its partner highlighted in the focused window, with the index of
+    // the window. An unmatched bracket is drawn as an error.
+    brackets: Vec<BracketMark>,
+    brackets_win: usize,
+    // What the highlights were computed for
+    brackets_key: Option<BracketKey>,
+    // Keystrokes are being recorded as a keyboard macro
+    recording: bool,
+    scroll_off: usize,
+    // The placeholder is drawn instead of the windows
+    placeholder: bool,
+    // A prompt is shown in the message bar
+    prompting: bool,
+    // Frame being drawn and the frame which the terminal shows
+    grid: Grid,
+    front: Grid,
+    // The terminal is cleared before the next frame since its contents are unknown
+    clear_terminal: bool,
+    // Frames are wrapped in synchronized output mode so that terminals show them at once
+    sync_output: bool,
+}
+
+// Where `recenter` puts the cursor line in the window. Repeating the command cycles them.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RecenterPos {
+    Middle,
+    Top,
+    Bottom,
+}
+
+impl RecenterPos {
+    pub fn next(self) -> Self {
+        match self {
+            RecenterPos::Middle => RecenterPos::Top,
+            RecenterPos::Top => RecenterPos::Bottom,
+            RecenterPos::Bottom => RecenterPos::Middle,
+        }
+    }
 }
 
 impl<W: Write> Screen<W> {
-    pub fn new(size: Option<(u16, u16)>, mut output: W) -> Result<Self> {
+    pub fn new(size: Option<(u16, u16)>, mut output: W, status_bar: StatusBar) -> Result<Self> {
         let (w, h) = if let Some(s) = size {
             s
         } else {
             get_window_size()?
         };
 
-        if too_small_window(w, h) {
-            return Err(Error::TooSmallWindow(w, h));
-        }
-        
         execute!(output, terminal::EnterAlternateScreen)?;
         execute!(output, terminal::Clear(terminal::ClearType::All))?;
 
-        Ok(Self {
+        let mut screen = Self {
             output,
-            rx: 0,
             num_cols: w as usize,
-            num_rows: h.saturating_sub(2) as usize,
+            num_rows: h as usize,
             message: Some(StatusMessage::new(
                 "Ctrl-? for help",
                 StatusMessageKind::Info,
             )),
             draw_message: DrawMessage::Open,
-            dirty_start: Some(0),
             sigwinch: SigwinchWatcher::new()?,
             cursor_moved: true,
-            rowoff: 0,
-            coloff: 0,
-        })
+            windows: vec![Window::new(0, status_bar)],
+            layout: Layout::new(0),
+            active: 0,
+            area: Rect::default(),
+            gutter: Gutter::default(),
+            theme: Theme::Dark,
+            message_timeout: None,
+            hints: vec![],
+            draw_hints: false,
+            mode: None,
+            brackets: vec![],
+            brackets_win: 0,
+            brackets_key: None,
+            recording: false,
+            scroll_off: 0,
+            placeholder: false,
+            prompting: false,
+            grid: Grid::new(w as usize, h as usize),
+            front: Grid::new(w as usize, h as usize),
+            clear_terminal: false,
+            sync_output: false,
+        };
+        screen.arrange();
+        Ok(screen)
     }
 
     fn write_flush(&mut self, bytes: &[u8]) -> Result<()> {
-        self.output.write(bytes)?;
+        self.output.write_all(bytes)?;
         self.output.flush()?;
         Ok(())
     }
 
-    fn write(&mut self, bytes: &[u8]) -> Result<()> {
-        self.output.write(bytes)?;
-        Ok(())
+    fn trim_line<S: AsRef<str>>(&self, line: &S) -> String {
+        let line = line.as_ref();
+        let coloff = self.windows[self.active].coloff;
+        if line.len() <= coloff {
+            return "".to_string();
+        }
+        line.chars().skip(coloff).take(self.num_cols).collect()
     }
 
-    fn flush(&mut self) -> Result<()> {
-        self.output.flush()?;
-        Ok(())
+    // Draws the part of the row between render columns `span.0` and `span.1`. `last` is true when
+    // the span reaches the end of the row.
+    fn draw_row(
+        grid: &mut Grid,
+        row: &Row,
+        highlight: &RowHighlight,
+        span: (usize, usize),
+        last: bool,
+        num_cols: usize,
+    ) -> usize {
+        let (start, end) = span;
+
+        let mut col = 0;
+        for c in row.render_text().chars() {
+            let rx = col;
+            let width = c.width_cjk().unwrap_or(1);
+            col += width;
+            if col <= start {
+                continue;
+            } else if col > end {
+                col = rx;
+                break;
+            }
+
+            grid.set_color(highlight.color_at(rx));
+            grid.put_char(c, width);
+        }
+
+        // Cursors and selections past the end of line are drawn as one trailing cell
+        if last && col >= start && col < num_cols + start {
+            let next = highlight.color_at(col);
+            if next != Color::Reset {
+                grid.set_color(next);
+                grid.put_char(' ', 1);
+                col += 1;
+            }
+        }
+        grid.set_color(Color::Reset);
+
+        col.saturating_sub(start)
     }
 
-    fn trim_line<S: AsRef<str>>(&self, line: &S) -> String {
-        let line = line.as_ref();
-        if line.len() <= self.coloff {
-            return "".to_string();
+    // Lines shown in the window as pairs of a row index and a span of render columns. Long rows
+    // take several lines in soft-wrap mode.
+    fn visual_lines(win: &Window, rows: &[Row], wrap: bool) -> Vec<(usize, (usize, usize), bool)> {
+        let num_rows = win.text_rows();
+        let num_cols = win.text_cols();
+        let mut lines = Vec::with_capacity(num_rows);
+
+        if !wrap {
+            let span = (win.coloff, win.coloff + num_cols);
+            for y in win.rowoff..win.rowoff + num_rows {
+                lines.push((y, span, true));
+            }
+            return lines;
         }
-        line.chars().skip(self.coloff).take(self.num_cols).collect()
+
+        let mut y = win.rowoff;
+        let mut skip = win.wrapoff;
+        while lines.len() < num_rows {
+            let row = match rows.get(y) {
+                Some(row) => row,
+                None => {
+                    lines.push((y, (0, num_cols), true));
+                    y += 1;
+                    continue;
+                }
+            };
+            let segments = win.segments_of(Some(row));
+            let count = segments.len();
+            for (k, (s, e)) in segments.into_iter().enumerate().skip(skip) {
+                if lines.len() == num_rows {
+                    break;
+                }
+                let start = row.rx_from_cx(s);
+                let end = cmp::min(row.rx_from_cx(e), start + num_cols);
+                lines.push((y, (start, end), k + 1 == count));
+            }
+            skip = 0;
+            y += 1;
+        }
+        lines
     }
 
-    fn draw_rows(
-        &mut self,
-        dirty_start: usize,
-        rows: &[Row],
-    ) -> Result<()> {
+    fn draw_rows(&self, grid: &mut Grid, win: &Window, dirty_start: usize, text_buf: &TextBuffer, focused: bool) {
+        let rows = text_buf.rows();
         let row_len = rows.len();
+        let num_cols = win.text_cols();
+        let reaches_edge = win.rect.left + win.rect.width >= self.num_cols && !win.separator;
+        let mut prev_row = None;
 
-        for y in 0..self.rows() {
-            let file_row = y + self.rowoff;
+        for (y, (file_row, span, last)) in
+            Self::visual_lines(win, rows, text_buf.wrap()).into_iter().enumerate()
+        {
+            // Only the first visual line of a row has its line number
+            let first = prev_row != Some(file_row);
+            prev_row = Some(file_row);
 
             if file_row < dirty_start {
                 continue;
             }
 
-            execute!(self.output, cursor::MoveTo(0, y as u16))?;
+            grid.move_to(win.rect.top + y, win.rect.left);
+
+            if win.gutter_width > 0 {
+                let line = if file_row < row_len && first { Some(file_row) } else { None };
+                let label = self.gutter.label(line, win.gutter_cy, win.gutter_width);
+                let color = if line == Some(win.gutter_cy) {
+                    Color::CursorLineNumber
+                } else {
+                    Color::LineNumber
+                };
+                grid.set_color(color);
+                grid.put_str(&label);
+                grid.set_color(Color::Reset);
+            }
 
-            let mut buf = Vec::with_capacity(0);
-            if file_row >= row_len {
-                self.write(b"~")?;
+            let width = if file_row >= row_len {
+                grid.set_color(Color::NonText);
+                grid.put_char('~', 1);
+                grid.set_color(Color::Reset);
+                1
             } else {
                 let row = &rows[file_row];
+                let highlight = RowHighlight::new(row, file_row, text_buf, focused, &self.brackets);
+                Self::draw_row(grid, row, &highlight, span, last, num_cols)
+            };
 
-                let mut col = 0;
-                for c in row.render_text().chars() {
-                    col += c.width_cjk().unwrap_or(1);
-                    if col <= self.coloff {
-                        continue;
-                    } else if col > self.num_cols + self.coloff {
-                        break;
-                    }
-                    
-                    write!(buf, "{}", c);
+            if reaches_edge {
+                grid.clear_line();
+            } else {
+                for _ in width..num_cols {
+                    grid.put_char(' ', 1);
                 }
             }
 
-            self.write(&buf)?;
-            self.write(b"\x1b[K")?;
+            if win.separator {
+                grid.set_color(Color::NonText);
+                grid.put_char('│', 1);
+                grid.set_color(Color::Reset);
+            }
         }
-        
-        Ok(())
     }
 
-    fn next_coloff(&self, want_stop: usize, row: &Row) -> usize {
-        let mut coloff = 0;
-        for c in row.render_text().chars() {
-            coloff += c.width_cjk().unwrap_or(1);
-            if coloff >= want_stop {
-                break;
-            }
+    fn draw_status_bar(&self, grid: &mut Grid, win: &Window, focused: bool) {
+        let rect = win.rect;
+        if rect.height == 0 {
+            return;
         }
-        coloff
+        grid.move_to(rect.top + rect.height - 1, rect.left);
+
+        let color = if focused { Color::StatusBar } else { Color::InactiveStatusBar };
+        grid.set_color(color);
+        grid.put_str(&win.status_bar.text(rect.width));
+        grid.set_color(Color::Reset);
     }
 
-    fn draw_status_bar<B: Write>(&self, mut buf: B, status_bar: &StatusBar) -> Result<()> {
-        write!(buf, "\x1b[{}H", self.rows() + 1)?;
+    fn draw_message_bar(&self, grid: &mut Grid, message: &StatusMessage) {
+        let text = truncate_to_width(&message.text, self.num_cols);
 
-        let left = status_bar.left();
-        let left = &left[..cmp::min(left.len(), self.num_cols)];
-        buf.write(left.as_bytes())?;
+        grid.move_to(self.num_rows - 1, 0);
 
-        let rest_len = self.num_cols - left.len();
-        if rest_len == 0 {
-            return Ok(());
+        if message.kind == StatusMessageKind::Error {
+            grid.set_color(Color::Error);
         }
+        grid.put_str(text);
+        grid.set_color(Color::Reset);
+        grid.clear_line();
+    }
 
-        let right = status_bar.right();
-        if right.len() > rest_len {
-            for _ in 0..rest_len {
-                buf.write(b" ")?;
-            }
-            return Ok(());
+    // The panel is put at the bottom of the area of windows with a horizontal rule above it
+    fn draw_hints(&self, grid: &mut Grid) {
+        let height = cmp::min(self.hints.len() + 1, self.area.height);
+        let top = self.area.height - height;
+
+        grid.move_to(top, 0);
+        grid.set_color(Color::NonText);
+        for _ in 0..self.num_cols {
+            grid.put_char('─', 1);
         }
+        grid.set_color(Color::Reset);
 
-        for _ in 0..rest_len - right.len() {
-            buf.write(b" ")?;
+        for (i, line) in self.hints.iter().take(height - 1).enumerate() {
+            grid.move_to(top + i + 1, 0);
+            grid.put_str(line);
+            grid.clear_line();
         }
-        buf.write(right.as_bytes())?;
+    }
 
-        Ok(())
+    fn arrange(&mut self) {
+        let area = Rect {
+            top: 0,
+            left: 0,
+            width: self.num_cols,
+            height: self.area_height(),
+        };
+        if area == self.area {
+            return;
+        }
+        self.area = area;
+
+        for (id, rect, separator) in self.layout.arrange(area) {
+            let win = &mut self.windows[id];
+            if win.rect != rect || win.separator != separator {
+                win.rect = rect;
+                win.separator = separator;
+                win.redraw_all();
+            }
+        }
     }
 
-    fn draw_message_bar<B: Write>(&self, mut buf: B, message: &StatusMessage) -> Result<()> {
-        let text = &message.text[..cmp::min(message.text.len(), self.num_cols)];
+    fn cursor_pos(&self) -> (u16, u16) {
+        let win = &self.windows[self.active];
+        let row = win.rect.top + win.cursor_row;
+        let col = win.text_left() + win.rx.saturating_sub(win.coloff);
+        (col as u16, row as u16)
+    }
 
-        write!(buf, "\x1b[{}H", self.num_rows + 2)?;
+    fn needs_redraw(&self) -> bool {
+        self.windows.iter().any(|w| w.dirty_start.is_some() || w.status_bar.redraw)
+            || self.draw_message != DrawMessage::DoNothing
+            || self.draw_hints
+    }
 
-        buf.write(text.as_bytes())?;
-        buf.write(b"\x1b[K")?;
-        Ok(())
+    // Windows which have no room for text are not drawn at all. A placeholder fills the area of
+    // the windows until the terminal grows again.
+    fn too_small(&self) -> bool {
+        self.windows.iter().any(|w| w.text_rows() == 0 || w.text_cols() == 0)
     }
 
-    fn do_scroll(&mut self, rows: &[Row], (cx, cy): (usize, usize)) {
-        let prev_rowoff = self.rowoff;
-        let prev_coloff = self.coloff;
+    fn draw_placeholder(&mut self) -> Result<()> {
+        let mut grid = mem::take(&mut self.grid);
+        for y in 0..self.area_height() {
+            grid.move_to(y, 0);
+            if y == 0 {
+                grid.set_color(Color::NonText);
+                grid.put_str(truncate_to_width(TOO_SMALL, self.num_cols));
+                grid.set_color(Color::Reset);
+            }
+            grid.clear_line();
+        }
+
+        if self.message_bar_shown() {
+            if let Some(message) = &self.message {
+                self.draw_message_bar(&mut grid, message);
+            }
+        }
 
-        if cy < rows.len() {
-            self.rx = rows[cy].rx_from_cx(cx);
-        } else {
-            self.rx = 0;
+        self.grid = grid;
+        self.flush_frame(None)
+    }
+
+    // Sends the cells changed since the last frame in one write. The cursor is left hidden when
+    // no position is given.
+    fn flush_frame(&mut self, cursor: Option<(u16, u16)>) -> Result<()> {
+        let mut buf = Vec::with_capacity(self.num_cols * self.num_rows);
+        if self.sync_output {
+            buf.write_all(b"\x1b[?2026h")?;
         }
+        queue!(buf, cursor::Hide)?;
 
-        if cy < self.rowoff {
-            self.rowoff = cy;
+        if self.clear_terminal {
+            queue!(buf, terminal::Clear(terminal::ClearType::All))?;
+            self.front = Grid::new(self.num_cols, self.num_rows);
+            self.clear_terminal = false;
         }
-        if cy >= self.rowoff + self.rows() {
-            self.rowoff = cy - self.rows() + 1;
+        self.grid.diff(&mut self.front, self.theme, &mut buf)?;
+
+        if let Some((col, row)) = cursor {
+            // Insert mode of modal editing is distinguished with a bar cursor
+            let shape = match self.mode {
+                Some(Mode::Insert) => cursor::CursorShape::Line,
+                _ => cursor::CursorShape::Block,
+            };
+            queue!(buf, cursor::MoveTo(col, row), cursor::Show, cursor::SetCursorShape(shape))?;
         }
-        if self.rx < self.coloff {
-            self.coloff = self.rx;
+        if self.sync_output {
+            buf.write_all(b"\x1b[?2026l")?;
         }
-        if self.rx >= self.coloff + self.num_cols {
-            self.coloff = self.next_coloff(self.rx - self.num_cols + 1, &rows[cy]);
+
+        self.write_flush(&buf)
+    }
+
+    fn redraw(&mut self, bufs: &[TextBuffer]) -> Result<()> {
+        let too_small = self.too_small();
+        if too_small != self.placeholder {
+            self.placeholder = too_small;
+            self.redraw_all();
         }
 
-        if prev_rowoff != self.rowoff || prev_coloff != self.coloff {
-            self.set_dirty_start(self.rowoff);
+        if too_small {
+            if self.needs_redraw() {
+                self.draw_placeholder()?;
+            }
+            return Ok(());
         }
-    }
 
-    fn redraw(
-        &mut self,
-        text_buf: &TextBuffer,
-        status_bar: &StatusBar,
-    ) -> Result<()> {
-        let cursor_row = text_buf.cy() - self.rowoff + 1;
-        let cursor_col = self.rx - self.coloff + 1;
+        let cursor = self.cursor_pos();
         let draw_message = self.draw_message;
 
-        if self.dirty_start.is_none()
-            && !status_bar.redraw
-            && draw_message == DrawMessage::DoNothing
-        {
+        if !self.needs_redraw() {
             if self.cursor_moved {
-                execute!(self.output, cursor::MoveTo((cursor_col - 1) as u16, (cursor_row - 1) as u16))?;
+                execute!(self.output, cursor::MoveTo(cursor.0, cursor.1))?;
                 self.output.flush()?;
             }
             return Ok(())
         }
 
-        execute!(self.output, cursor::Hide)?;
-
-        if let Some(s) = self.dirty_start {
-            self.draw_rows(s, text_buf.rows())?;
+        let mut grid = mem::take(&mut self.grid);
+        for (i, win) in self.windows.iter().enumerate() {
+            if let Some(s) = win.dirty_start {
+                self.draw_rows(&mut grid, win, s, &bufs[win.buf_idx], i == self.active);
+            }
+            if win.status_bar.redraw {
+                self.draw_status_bar(&mut grid, win, i == self.active);
+            }
         }
 
-        if status_bar.redraw
-            || draw_message == DrawMessage::Open
-            || draw_message == DrawMessage::Close
-        {
-            let mut buf = Vec::with_capacity(1 * self.num_cols);
-            self.draw_status_bar(&mut buf, status_bar)?;
-            self.write(&buf)?;
+        // Windows may be drawn over the panel so it is always drawn after them
+        if !self.hints.is_empty() {
+            self.draw_hints(&mut grid);
         }
 
-        if draw_message == DrawMessage::Update || draw_message == DrawMessage::Open {
+        let open = draw_message == DrawMessage::Update || draw_message == DrawMessage::Open;
+        if open && self.message_bar_shown() {
             if let Some(message) = &self.message {
-                let mut buf = Vec::with_capacity(1 * self.num_cols);
-                self.draw_message_bar(&mut buf, message)?;
-                self.write(&buf)?;
+                self.draw_message_bar(&mut grid, message);
             }
         }
 
-        execute!(self.output, cursor::MoveTo((cursor_col - 1) as u16, (cursor_row - 1) as u16))?;
-        execute!(self.output, cursor::Show, cursor::SetCursorShape(cursor::CursorShape::Block))?;
-
-        self.flush()?;
-
-        Ok(())
+        self.grid = grid;
+        self.flush_frame(Some(cursor))
     }
 
     fn after_render(&mut self) {
-        self.dirty_start = None;
+        for win in self.windows.iter_mut() {
+            win.dirty_start = None;
+            win.status_bar.redraw = false;
+        }
         self.cursor_moved = false;
         self.draw_message = DrawMessage::DoNothing;
+        self.draw_hints = false;
     }
 
-    pub fn render_welcome(&mut self, status_bar: &StatusBar) -> Result<()> {
-        self.write_flush(b"\x1b[?25l")?;
+    // Rows whose bracket highlights change are redrawn
+    fn update_brackets(&mut self, bufs: &[TextBuffer]) {
+        // Finding the partner scans the whole buffer, so it is done only after the cursor or the
+        // buffer changed
+        let buf_idx = self.windows[self.active].buf_idx;
+        let buf = &bufs[buf_idx];
+        let key = (self.active, buf_idx, buf.cursor(), buf.version(), buf.filetype());
+        if self.brackets_key == Some(key) {
+            return;
+        }
+        self.brackets_key = Some(key);
+
+        let brackets = match buf.matching_bracket() {
+            Some(BracketMatch::Matched(at, partner)) => {
+                vec![(at, Color::MatchingBracket), (partner, Color::MatchingBracket)]
+            }
+            Some(BracketMatch::Unmatched(at)) => vec![(at, Color::Error)],
+            None => vec![],
+        };
+        if self.brackets_win == self.active && self.brackets == brackets {
+            return;
+        }
+
+        let top = |marks: &[BracketMark]| marks.iter().map(|&((_, y), _)| y).min();
+        if let (Some(win), Some(y)) = (self.windows.get_mut(self.brackets_win), top(&self.brackets)) {
+            win.set_dirty_start(y);
+        }
+        if let Some(y) = top(&brackets) {
+            self.windows[self.active].set_dirty_start(y);
+        }
+        self.brackets = brackets;
+        self.brackets_win = self.active;
+    }
 
-        let mut buf = Vec::with_capacity((self.rows() + 2 + self.num_cols) * 3);
+    fn update_windows(&mut self, bufs: &[TextBuffer]) {
+        self.arrange();
+        self.update_brackets(bufs);
+        let num_bufs = bufs.len();
+        for (i, win) in self.windows.iter_mut().enumerate() {
+            let buf = &bufs[win.buf_idx];
+            let cursor = if i == self.active { buf.cursor() } else { win.cursor };
+            win.status_bar.set_buf_pos((win.buf_idx + 1, num_bufs));
+            win.status_bar.update_from_buf(buf, cursor.1);
+            win.status_bar.set_mode(if i == self.active { self.mode.map(Mode::name) } else { None });
+            win.status_bar.set_recording(i == self.active && self.recording);
+            win.update_gutter(&self.gutter, buf.rows().len(), cursor.1);
+            win.do_scroll(buf.rows(), cursor, buf.wrap(), self.scroll_off);
+        }
+    }
 
+    pub fn render_welcome(&mut self, bufs: &[TextBuffer]) -> Result<()> {
+        self.update_windows(bufs);
+        if self.too_small() {
+            return self.render(bufs);
+        }
+        let mut grid = mem::take(&mut self.grid);
         for y in 0..self.rows() {
-            write!(buf, "\x1b[{}H", y + 1)?;
+            grid.move_to(y, 0);
 
             if y == self.rows() / 3 {
                 let msg_buf = format!("Berry -- version {}", VERSION);
                 let welcome = self.trim_line(&msg_buf);
                 let padding = (self.num_cols - welcome.len()) / 2;
                 if padding > 0 {
-                    buf.write(b"~")?;
+                    grid.put_char('~', 1);
                     for _ in 0..padding - 1 {
-                        buf.write(b" ")?;
+                        grid.put_char(' ', 1);
                     }
                 }
-                buf.write(welcome.as_bytes())?;
+                grid.put_str(&welcome);
+            } else if y == 0 {
+                grid.put_char(' ', 1);
             } else {
-                if y == 0 {
-                    buf.write(b" ")?;
-                } else {
-                    buf.write(b"~")?;
-                }
+                grid.put_char('~', 1);
             }
 
-            buf.write(b"\x1b[K")?;
+            grid.clear_line();
         }
 
-        self.draw_status_bar(&mut buf, status_bar)?;
-        if let Some(message) = &self.message {
-            self.draw_message_bar(&mut buf, message)?;
+        self.draw_status_bar(&mut grid, &self.windows[self.active], true);
+        if self.message_bar_shown() {
+            if let Some(message) = &self.message {
+                self.draw_message_bar(&mut grid, message);
+            }
         }
-        
-        write!(buf, "\x1b[H")?;
-        buf.write(b"\x1b[?25h")?;
-        self.write_flush(&buf);
+
+        self.grid = grid;
+        self.flush_frame(Some((0, 0)))?;
 
         self.after_render();
         Ok(())
     }
 
-    pub fn render(
-        &mut self,
-        buf: &TextBuffer,
-        status_bar: &StatusBar,
-    ) -> Result<()> {
-        self.do_scroll(buf.rows(), buf.cursor());
-        self.redraw(buf, status_bar)?;
+    fn expire_message(&mut self) {
+        let timeout = match self.message_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        if let Some(m) = &self.message {
+            if m.timestamp.elapsed().map(|d| d > timeout).unwrap_or(false) {
+                self.unset_message();
+            }
+        }
+    }
+
+    // Renders on an idle tick only when a timer such as the message expiry changed the screen
+    pub fn render_idle(&mut self, bufs: &[TextBuffer]) -> Result<()> {
+        self.expire_message();
+        if self.needs_redraw() {
+            self.render(bufs)?;
+        }
+        Ok(())
+    }
+
+    pub fn render(&mut self, bufs: &[TextBuffer]) -> Result<()> {
+        self.expire_message();
+        self.update_windows(bufs);
+        self.redraw(bufs)?;
         self.after_render();
         Ok(())
     }
 
-    pub fn set_dirty_start(&mut self, start: usize) {
-        if let Some(s) = self.dirty_start {
-            if s < start {
-                return;
-            }
+    pub fn set_dirty_start(&mut self, buf_idx: usize, start: usize) {
+        for win in self.windows.iter_mut().filter(|w| w.buf_idx == buf_idx) {
+            win.set_dirty_start(start);
+        }
+    }
+
+    fn redraw_all(&mut self) {
+        for win in self.windows.iter_mut() {
+            win.redraw_all();
+        }
+        if self.message.is_some() {
+            self.draw_message = self.draw_message.fold(DrawMessage::Update);
         }
-        self.dirty_start = Some(start);
     }
 
-    pub fn maybe_resize<I>(&mut self, input: I) -> Result<bool>
-    where
-        I: Iterator<Item = Result<InputSeq>>,
-    {
+    pub fn maybe_resize(&mut self) -> Result<bool> {
         if !self.sigwinch.notified() {
             return Ok(false);
         }
 
         let (w, h) = get_window_size()?;
-        if too_small_window(w, h) {
-            return Err(Error::TooSmallWindow(w, h));
-        }
-
-        self.num_rows = h.saturating_sub(2) as usize;
+        self.num_rows = h as usize;
         self.num_cols = w as usize;
-        self.dirty_start = Some(0);
+        self.grid = Grid::new(self.num_cols, self.num_rows);
+        // The terminal may have reflowed what it showed
+        self.clear_terminal = true;
+        self.arrange();
+        self.redraw_all();
 
         Ok(true)
     }
 
+    // Focus the window with the given ID. The cursor of the window losing focus is saved in it,
+    // and the buffer index and the saved cursor of the newly focused window are returned.
+    fn focus_window(&mut self, id: usize, cursor: (usize, usize)) -> (usize, (usize, usize)) {
+        if let Some(win) = self.windows.get_mut(self.active) {
+            win.cursor = cursor;
+            win.status_bar.redraw = true;
+        }
+        self.active = id;
+        let win = &mut self.windows[id];
+        win.status_bar.redraw = true;
+        self.cursor_moved = true;
+        (win.buf_idx, win.cursor)
+    }
+
+    // Shows another buffer in the focused window
+    pub fn show_buffer(&mut self, buf_idx: usize) {
+        let win = &mut self.windows[self.active];
+        win.buf_idx = buf_idx;
+        win.rowoff = 0;
+        win.wrapoff = 0;
+        win.coloff = 0;
+        win.redraw_all();
+        self.cursor_moved = true;
+    }
+
+    pub fn split_window(&mut self, dir: SplitDir, cursor: (usize, usize)) -> bool {
+        let new = self.windows.len();
+        let current = &self.windows[self.active];
+        if !self.layout.split(self.active, new, dir, current.rect) {
+            return false;
+        }
+
+        let mut win = Window::new(current.buf_idx, current.status_bar.clone());
+        win.rowoff = current.rowoff;
+        win.coloff = current.coloff;
+        win.cursor = cursor;
+        self.windows.push(win);
+        self.focus_window(new, cursor);
+        self.area = Rect::default();
+        self.arrange();
+        true
+    }
+
+    pub fn close_window(&mut self) -> Option<(usize, (usize, usize))> {
+        let order = self.layout.windows();
+        if order.len() == 1 {
+            return None;
+        }
+
+        let pos = order.iter().position(|&i| i == self.active).unwrap();
+        let removed = self.active;
+        self.layout.remove(removed);
+        self.windows.remove(removed);
+
+        let mut next = order[if pos == 0 { 1 } else { pos - 1 }];
+        if next > removed {
+            next -= 1;
+        }
+        self.active = usize::MAX;
+        let focused = self.focus_window(next, (0, 0));
+        self.area = Rect::default();
+        self.arrange();
+        Some(focused)
+    }
+
+    pub fn cycle_window(&mut self, forward: bool, cursor: (usize, usize)) -> Option<(usize, (usize, usize))> {
+        let order = self.layout.windows();
+        if order.len() == 1 {
+            return None;
+        }
+        let pos = order.iter().position(|&i| i == self.active).unwrap();
+        let next = if forward {
+            order[(pos + 1) % order.len()]
+        } else {
+            order[(pos + order.len() - 1) % order.len()]
+        };
+        Some(self.focus_window(next, cursor))
+    }
+
+    pub fn resize_window(&mut self, delta: isize) -> bool {
+        if !self.layout.resize(self.active, delta) {
+            return false;
+        }
+        self.area = Rect::default();
+        self.arrange();
+        true
+    }
+
+    pub fn text_cols(&self) -> usize {
+        self.windows[self.active].text_cols()
+    }
+
+    pub fn text_rows(&self) -> usize {
+        self.windows[self.active].text_rows()
+    }
+
+    // Scrolls the focused window by `delta` lines without moving the cursor. Returns how many lines
+    // the cursor needs to move down (or up when negative) to stay in the window.
+    pub fn scroll_view(&mut self, buf: &TextBuffer, delta: isize) -> isize {
+        self.arrange();
+        let win = &mut self.windows[self.active];
+        // The cursor may have moved since the window was last drawn
+        win.do_scroll(buf.rows(), buf.cursor(), buf.wrap(), self.scroll_off);
+        win.scroll_by(buf.rows(), delta, buf.wrap(), self.scroll_off)
+    }
+
+    pub fn recenter(&mut self, buf: &TextBuffer, pos: RecenterPos) {
+        self.arrange();
+        let win = &mut self.windows[self.active];
+        let last = win.text_rows().saturating_sub(1);
+        let margin = cmp::min(self.scroll_off, last / 2);
+        let at = match pos {
+            RecenterPos::Middle => last / 2,
+            RecenterPos::Top => margin,
+            RecenterPos::Bottom => last - margin,
+        };
+        win.scroll_cursor_to(buf.rows(), buf.cursor(), at, buf.wrap());
+    }
+
+    pub fn apply_config(&mut self, config: &Config) {
+        // Cells keep colors rather than sequences so every cell is sent again in the new theme
+        if self.theme != config.theme {
+            self.clear_terminal = true;
+        }
+        self.theme = config.theme;
+        self.message_timeout = config.message_timeout;
+        self.gutter.line_numbers = config.line_numbers;
+        self.scroll_off = config.scroll_off;
+        self.sync_output = config.sync_output;
+        self.redraw_all();
+    }
+
+    pub fn set_mode(&mut self, mode: Option<Mode>) {
+        self.mode = mode;
+    }
+
+    pub fn set_prompting(&mut self, prompting: bool) {
+        self.prompting = prompting;
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    pub fn cycle_line_numbers(&mut self) -> LineNumbers {
+        self.gutter.line_numbers = self.gutter.line_numbers.next();
+        self.redraw_all();
+        self.gutter.line_numbers
+    }
+
+    // Shows entries of key and description in columns. Entries which do not fit are omitted.
+    pub fn set_hints(&mut self, entries: &[(String, String)]) {
+        const ARROW: &str = " → ";
+        let key_width = entries.iter().map(|(k, _)| text_width(k)).max().unwrap_or(0);
+        let desc_width = entries.iter().map(|(_, d)| text_width(d)).max().unwrap_or(0);
+        let cell_width = key_width + text_width(ARROW) + desc_width + 2;
+        let num_columns = cmp::max(self.num_cols / cell_width, 1);
+
+        self.hints = entries
+            .chunks(num_columns)
+            .map(|chunk| {
+                // Keys are aligned to the right and descriptions to the left
+                let mut line = String::new();
+                for (key, desc) in chunk {
+                    line.push_str(&" ".repeat(key_width - text_width(key)));
+                    line.push_str(key);
+                    line.push_str(ARROW);
+                    line.push_str(desc);
+                    line.push_str(&" ".repeat(desc_width - text_width(desc) + 2));
+                }
+                truncate_to_width(line.trim_end(), self.num_cols).to_string()
+            })
+            .collect();
+        self.draw_hints = true;
+    }
+
+    pub fn unset_hints(&mut self) {
+        if !self.hints.is_empty() {
+            self.hints.clear();
+            self.redraw_all();
+        }
+    }
+
     pub fn set_info_message<S: Into<String>>(&mut self, message: S) {
         self.set_message(Some(StatusMessage::new(message, StatusMessageKind::Info)));
     }
@@ -430,21 +1018,31 @@ impl<W: Write> Screen<W> {
         self.message = m;
     }
 
-    pub fn rows(&self) -> usize {
-        if self.message.is_some() {
-            self.num_rows
+    // The message bar is hidden while the terminal is too short to show a line of text and a
+    // status bar above it. A prompt is always shown since its input would be invisible otherwise.
+    fn message_bar_shown(&self) -> bool {
+        self.message.is_some() && (self.num_rows >= 3 || self.prompting && self.num_rows >= 1)
+    }
+
+    // Height of the area of windows
+    fn area_height(&self) -> usize {
+        if self.message_bar_shown() {
+            self.num_rows - 1
         } else {
-            self.num_rows + 1
+            self.num_rows
         }
     }
 
-    pub fn cols(&self) -> usize {
-        self.num_cols
+    // Rows of the area of windows above the status bar at the bottom
+    pub fn rows(&self) -> usize {
+        self.area_height().saturating_sub(1)
     }
 
-    pub fn force_set_cursor(&mut self, row: usize, col: usize) -> Result<()> {
-        write!(self.output, "\x1b[{};{}H", row, col)?;
-        self.output.flush()?;
+    // Puts the cursor at the column of the message bar where the input of a prompt is edited.
+    // The cursor is shown also when the placeholder hid it.
+    pub fn set_prompt_cursor(&mut self, col: usize) -> Result<()> {
+        write!(self.output, "\x1b[{};{}H", self.num_rows, col)?;
+        execute!(self.output, cursor::Show)?;
         Ok(())
     }
 }
@@ -1,6 +1,9 @@
 use crate::error::Result;
 use signal_hook::consts::SIGWINCH;
 use signal_hook::{self, SigId};
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
@@ -27,3 +30,42 @@ impl Drop for SigwinchWatcher {
     }
 }
 
+
+// Each SIGWINCH writes a byte to a socket pair so that `poll(2)` on the reading end wakes up even
+// when the signal arrives just before the call
+pub struct SigwinchWaker {
+    reader: UnixStream,
+    signal_id: SigId,
+}
+
+impl SigwinchWaker {
+    pub fn new() -> Result<Self> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+        let signal_id = signal_hook::low_level::pipe::register(SIGWINCH, writer)?;
+        Ok(Self { reader, signal_id })
+    }
+
+    // Consumes the bytes written by the signals received so far
+    pub fn drain(&mut self) {
+        let mut buf = [0; 16];
+        while let Ok(n) = self.reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+        }
+    }
+}
+
+impl AsRawFd for SigwinchWaker {
+    fn as_raw_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+}
+
+impl Drop for SigwinchWaker {
+    fn drop(&mut self) {
+        signal_hook::low_level::unregister(self.signal_id);
+    }
+}
@@ -1,11 +1,58 @@
 
 use crate::text_buffer::TextBuffer;
+use std::cmp;
+use unicode_width::UnicodeWidthChar;
 
+// ASCII so that its width does not depend on the terminal
+const ELLIPSIS: &str = "...";
+const MIN_NAME_WIDTH: usize = 10;
+
+// Width of the text in terminal columns
+pub fn text_width(text: &str) -> usize {
+    text.chars().map(|c| c.width_cjk().unwrap_or(1)).sum()
+}
+
+// Longest prefix of the text which fits in `width` columns. A wide character is never split.
+pub fn truncate_to_width(text: &str, width: usize) -> &str {
+    let mut col = 0;
+    for (i, c) in text.char_indices() {
+        col += c.width_cjk().unwrap_or(1);
+        if col > width {
+            return &text[..i];
+        }
+    }
+    text
+}
+
+// Keeps the end of the text which fits in `width` columns with an ellipsis in place of the cut
+fn truncate_start(text: &str, width: usize) -> String {
+    if text_width(text) <= width {
+        return text.to_string();
+    }
+    if width < ELLIPSIS.len() {
+        return "".to_string();
+    }
+    let mut col = ELLIPSIS.len();
+    let mut start = text.len();
+    for (i, c) in text.char_indices().rev() {
+        col += c.width_cjk().unwrap_or(1);
+        if col > width {
+            break;
+        }
+        start = i;
+    }
+    format!("{}{}", ELLIPSIS, &text[start..])
+}
+
+#[derive(Clone)]
 pub struct StatusBar {
     pub modified: bool,
     pub filename: String,
     pub buf_pos: (usize, usize),
     pub line_pos: (usize, usize),
+    // Editing mode such as "NORMAL" in vi profile
+    pub mode: Option<&'static str>,
+    pub recording: bool,
     pub redraw: bool,
 }
 
@@ -33,6 +80,8 @@ impl StatusBar {
     setter!(set_modified, modified, bool);
     setter!(set_filename, filename, &str, filename.to_string());
     setter!(set_line_pos, line_pos, (usize, usize));
+    setter!(set_mode, mode, Option<&'static str>);
+    setter!(set_recording, recording, bool);
     
     pub fn from_buffer(buf: &TextBuffer, buf_pos: (usize, usize)) -> Self {
         Self {
@@ -40,29 +89,69 @@ impl StatusBar {
             filename: buf.filename().to_string(),
             buf_pos,
             line_pos: (buf.cy() + 1, buf.rows().len()),
+            mode: None,
+            recording: false,
             redraw: false,
         }
     }
 
-    pub fn left(&self) -> String {
-        format!(
-            "{:<20?} - {}/{} {}",
-            self.filename,
+    // The left side is split around the file name so that only the name is shortened
+    fn left(&self) -> (String, String, String) {
+        let mode = match self.mode {
+            Some(mode) => format!("[{}] ", mode),
+            None => "".to_string(),
+        };
+        let prefix = format!("{}{}", mode, if self.recording { "[REC] " } else { "" });
+        let suffix = format!(
+            " - {}/{} {}",
             self.buf_pos.0,
             self.buf_pos.1,
             if self.modified { "(modified) " } else { "" }
-        )
+        );
+        (prefix, format!("{:?}", self.filename), suffix)
     }
 
-    pub fn right(&self) -> String {
+    fn right(&self) -> String {
         let (y, len) = self.line_pos;
         format!("{}/{}", y, len)
     }
 
-    pub fn update_from_buf(&mut self, buf: &TextBuffer) {
+    // Lays out the bar in `width` columns. The line position on the right is always shown and a
+    // long file name is cut from the left so that the end of the path stays visible.
+    pub fn text(&self, width: usize) -> String {
+        let right = self.right();
+        let right_width = text_width(&right);
+        if right_width >= width {
+            return truncate_to_width(&right, width).to_string();
+        }
+
+        // At least one space separates both sides
+        let left_width = width - right_width - 1;
+        let (prefix, name, suffix) = self.left();
+        let name_width = text_width(&name);
+        // Some of the name is kept even when the rest of the left side is cut instead
+        let room = left_width.saturating_sub(text_width(&prefix) + text_width(&suffix));
+        let room = cmp::max(room, cmp::min(name_width, MIN_NAME_WIDTH));
+        let name = if name_width <= room {
+            // Names are padded to 20 columns so that the rest of the bar moves less
+            let padding = cmp::min(20usize.saturating_sub(name_width), room - name_width);
+            format!("{}{}", name, " ".repeat(padding))
+        } else {
+            // Quotes of the name are kept around the shortened path
+            let inner = &name[1..name.len() - 1];
+            format!("\"{}\"", truncate_start(inner, room.saturating_sub(2)))
+        };
+
+        let left = format!("{}{}{}", prefix, name, suffix);
+        let left = truncate_to_width(&left, left_width);
+        let padding = width - text_width(left) - right_width;
+        format!("{}{}{}", left, " ".repeat(padding), right)
+    }
+
+    pub fn update_from_buf(&mut self, buf: &TextBuffer, cy: usize) {
         self.set_modified(buf.modified());
         self.set_filename(buf.filename());
-        self.set_line_pos((buf.cy() + 1, buf.rows().len()));
+        self.set_line_pos((cy + 1, buf.rows().len()));
     }
 }
 
@@ -3,5 +3,46 @@
 pub enum Color {
     Reset,
     NonText,
+    Cursor,
+    Selection,
+    MatchingBracket,
+    Error,
+    StatusBar,
+    InactiveStatusBar,
+    LineNumber,
+    CursorLineNumber,
 }
 
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Theme {
+    Dark,
+    Light,
+    // Only uses reverse video so that it works on terminals without colors
+    Mono,
+}
+
+impl Theme {
+    pub fn sequence(self, color: Color) -> &'static [u8] {
+        use Color::*;
+        match (self, color) {
+            (_, Reset) => b"\x1b[m",
+            (_, Cursor) => b"\x1b[7m",
+            (_, StatusBar) => b"\x1b[7m",
+            (_, MatchingBracket) => b"\x1b[1;4m",
+            (Theme::Dark, NonText) => b"\x1b[90m",
+            (Theme::Dark, Selection) => b"\x1b[48;5;238m",
+            (Theme::Dark, Error) => b"\x1b[91m",
+            (Theme::Dark, InactiveStatusBar) => b"\x1b[90;7m",
+            (Theme::Dark, LineNumber) => b"\x1b[90m",
+            (Theme::Dark, CursorLineNumber) => b"\x1b[93m",
+            (Theme::Light, NonText) => b"\x1b[37m",
+            (Theme::Light, Selection) => b"\x1b[48;5;252m",
+            (Theme::Light, Error) => b"\x1b[31m",
+            (Theme::Light, InactiveStatusBar) => b"\x1b[37;7m",
+            (Theme::Light, LineNumber) => b"\x1b[37m",
+            (Theme::Light, CursorLineNumber) => b"\x1b[33m",
+            (Theme::Mono, Selection) => b"\x1b[7m",
+            (Theme::Mono, _) => b"\x1b[m",
+        }
+    }
+}
@@ -1,8 +1,12 @@
+use crate::brackets::{self, BracketMatch};
+use crate::config::{AutoPairs, Config};
 use crate::edit_diff::{EditDiff, UndoRedo};
 use crate::error::Result;
+use crate::filetype::{self, FileType};
 use crate::history::History;
-use crate::row::Row;
+use crate::row::{Row, TAB_STOP};
 
+use std::cmp;
 use std::fs::File;
 use std::io::{self, BufRead, Write};
 use std::path::{Path, PathBuf};
@@ -38,16 +42,60 @@ pub enum CursorDir {
     Down,
 }
 
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Script of word characters. A word is a run of characters of the same kind so that CJK text
+// next to Latin letters, or Kanji followed by Kana, is not taken as one long word.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WordKind {
+    Alnum,
+    Han,
+    Hiragana,
+    Katakana,
+    Hangul,
+}
+
+pub fn word_kind(c: char) -> Option<WordKind> {
+    if !is_word_char(c) {
+        return None;
+    }
+    let kind = match c as u32 {
+        0x3005 | 0x3400..=0x4dbf | 0x4e00..=0x9fff | 0xf900..=0xfaff | 0x20000..=0x3134f => WordKind::Han,
+        0x3040..=0x309f => WordKind::Hiragana,
+        0x30a0..=0x30ff | 0x31f0..=0x31ff | 0xff66..=0xff9f => WordKind::Katakana,
+        0x1100..=0x11ff | 0x3130..=0x318f | 0xac00..=0xd7af => WordKind::Hangul,
+        _ => WordKind::Alnum,
+    };
+    Some(kind)
+}
+
 pub struct TextBuffer {
     cx: usize,
     cy: usize,
+    cursors: Vec<(usize, usize)>,
+    mark: Option<(usize, usize)>,
     file: Option<FilePath>,
     row: Vec<Row>,
     undo_count: i32,
     modified: bool,
     history: History,
     inserted_undo: bool,
+    // While non-zero, all edits are put in the ongoing undo group
+    undo_group: usize,
     dirty_start: Option<usize>,
+    wrap: bool,
+    tab_stop: usize,
+    expand_tab: bool,
+    filetype: FileType,
+    auto_indent: bool,
+    auto_pairs: AutoPairs,
+    // Display columns which consecutive vertical moves keep across short lines, with the positions
+    // where the moves left cursors. Cleared by edits and by any other motion.
+    goal_cols: Vec<((usize, usize), usize)>,
+    // Incremented by every change of the text including undo and redo
+    version: usize,
 }
 
 impl TextBuffer {
@@ -55,13 +103,24 @@ impl TextBuffer {
         Self {
             cx: 0,
             cy: 0,
+            cursors: vec![],
+            mark: None,
             file: None,
-            row: vec![Row::empty()],
+            row: vec![Row::empty(TAB_STOP)],
             undo_count: 0,
             modified: false,
             history: History::default(),
             inserted_undo: false,
+            undo_group: 0,
             dirty_start: Some(0),
+            wrap: false,
+            tab_stop: TAB_STOP,
+            expand_tab: false,
+            filetype: FileType::Plain,
+            auto_indent: true,
+            auto_pairs: AutoPairs::default(),
+            goal_cols: vec![],
+            version: 0,
         }
     }
 
@@ -71,6 +130,7 @@ impl TextBuffer {
         if !path.exists() {
             let mut buf = Self::empty();
             buf.file = file;
+            buf.filetype = FileType::detect(path);
             buf.undo_count = 0;
             buf.modified = false;
             return Ok(buf);
@@ -78,19 +138,30 @@ impl TextBuffer {
 
         let row = io::BufReader::new(File::open(path)?)
             .lines()
-            .map(|r| Row::new(r?))
+            .map(|r| Row::new(r?, TAB_STOP))
             .collect::<Result<_>>()?;
         
         Ok(Self {
             cx: 0,
             cy: 0,
+            cursors: vec![],
+            mark: None,
             file,
             row,
             undo_count: 0,
             modified: false,
             history: History::default(),
             inserted_undo: false,
+            undo_group: 0,
             dirty_start: Some(0),
+            wrap: false,
+            tab_stop: TAB_STOP,
+            expand_tab: false,
+            filetype: FileType::detect(path),
+            auto_indent: true,
+            auto_pairs: AutoPairs::default(),
+            goal_cols: vec![],
+            version: 0,
         })
     }
 
@@ -115,6 +186,7 @@ impl TextBuffer {
 
     pub fn set_file<S: Into<String>>(&mut self, file_path: S) {
         let file = FilePath::from_string(file_path);
+        self.filetype = FileType::detect(&file.path);
         self.file = Some(file);
     }
 
@@ -131,7 +203,15 @@ impl TextBuffer {
             return Ok("".to_string());
         };
 
-        let f = match File::create(&file.path) {
+        let bytes = self.write_file(&file.path)?;
+        self.undo_count = 0;
+        self.modified = false;
+        Ok(format!("{} bytes written to {}", bytes, &file.display))
+    }
+
+    // Writes the lines to the file and returns the number of bytes written
+    fn write_file(&self, path: &Path) -> std::result::Result<usize, String> {
+        let f = match File::create(path) {
             Ok(f) => f,
             Err(e) => return Err(format!("Could not save: {}", e)),
         };
@@ -140,15 +220,32 @@ impl TextBuffer {
         for line in self.row.iter() {
             let b = line.buffer();
             writeln!(f, "{}", b).map_err(|e| format!("Could not write to file: {}", e))?;
-            bytes += b.as_bytes().len() + 1;
+            bytes += b.len() + 1;
         }
         f.flush().map_err(|e| format!("Could not flush to file: {}", e))?;
+        Ok(bytes)
+    }
 
-        self.undo_count = 0;
-        self.modified = false;
+    // Writes a copy of the buffer to another file. The file name and the modified state of the
+    // buffer are not changed.
+    pub fn write_copy<S: Into<String>>(&self, file_path: S) -> std::result::Result<String, String> {
+        let file = FilePath::from_string(file_path);
+        let bytes = self.write_file(&file.path)?;
         Ok(format!("{} bytes written to {}", bytes, &file.display))
     }
 
+    // Saves the buffer to another file. The new file name is kept only when saving succeeded.
+    pub fn save_as<S: Into<String>>(&mut self, file_path: S) -> std::result::Result<String, String> {
+        let prev = self.file.replace(FilePath::from_string(file_path));
+        let result = self.save();
+        match (&result, &self.file) {
+            (Err(_), _) => self.file = prev,
+            (Ok(_), Some(file)) => self.filetype = FileType::detect(&file.path),
+            _ => {}
+        }
+        result
+    }
+
     fn set_dirty_start(&mut self, line: usize) {
         if let Some(l) = self.dirty_start {
             if l <= line {
@@ -159,19 +256,25 @@ impl TextBuffer {
     }
 
     fn apply_diff(&mut self, diff: &EditDiff, which: UndoRedo) {
-        let (x, y) = diff.apply(&mut self.row, which);
+        let (x, y) = diff.apply(&mut self.row, which, self.tab_stop);
         self.set_cursor(x, y);
         self.set_dirty_start(y);
+        self.goal_cols.clear();
+        self.version += 1;
     }
 
     fn new_diff(&mut self, diff: EditDiff) {
+        if self.mark.is_some() {
+            self.set_cursors_dirty();
+            self.mark = None;
+        }
         self.apply_diff(&diff, UndoRedo::Redo);
         self.modified = true;
         self.history.push(diff);
     }
 
     fn insert_undo_point(&mut self) {
-        if !self.inserted_undo {
+        if !self.inserted_undo && self.undo_group == 0 {
             if self.history.finish_ongoing_edit() {
                 self.undo_count = self.undo_count.saturating_add(1);
             }
@@ -180,6 +283,19 @@ impl TextBuffer {
         }
     }
 
+    // Edits until `end_undo_group` are undone at once even if they span several keystrokes. Groups
+    // can be nested, e.g. vi changes while playing a keyboard macro. The outermost group wins.
+    pub fn begin_undo_group(&mut self) {
+        if self.undo_group == 0 {
+            self.insert_undo_point();
+        }
+        self.undo_group += 1;
+    }
+
+    pub fn end_undo_group(&mut self) {
+        self.undo_group = self.undo_group.saturating_sub(1);
+    }
+
     pub fn finish_edit(&mut self) -> Option<usize> {
         self.inserted_undo = false;
         let dirty_start = self.dirty_start;
@@ -187,50 +303,328 @@ impl TextBuffer {
         dirty_start
     }
 
+    fn map_cursors<F: Fn(usize, usize) -> (usize, usize)>(&mut self, f: F) {
+        for c in self.cursors.iter_mut() {
+            *c = f(c.0, c.1);
+        }
+    }
+
+    fn set_cursors_dirty(&mut self) {
+        if self.cursors.is_empty() && self.mark.is_none() {
+            return;
+        }
+        let top = self
+            .cursors
+            .iter()
+            .chain(self.mark.iter())
+            .fold(self.cy, |acc, &(_, y)| cmp::min(acc, y));
+        self.set_dirty_start(top);
+    }
+
+    // Run `f` once at every cursor. Cursors are visited from the bottom of the buffer to the top
+    // and each edit primitive shifts the other cursors through `map_cursors`, so positions stay
+    // valid even when an edit adds or removes lines.
+    fn each_cursor<F: FnMut(&mut Self)>(&mut self, mut f: F) {
+        self.set_cursors_dirty();
+        if self.cursors.is_empty() {
+            f(self);
+            self.set_cursors_dirty();
+            return;
+        }
+
+        let primary = (self.cx, self.cy);
+        self.cursors.push(primary);
+        self.cursors.sort_by_key(|&(x, y)| cmp::Reverse((y, x)));
+        let mut primary_idx = self.cursors.iter().position(|&c| c == primary).unwrap();
+
+        for i in 0..self.cursors.len() {
+            let (x, y) = self.cursors[i];
+            self.set_cursor(x, y);
+            f(self);
+            self.cursors[i] = (self.cx, self.cy);
+        }
+
+        let primary = self.cursors[primary_idx];
+        self.cursors.dedup();
+        primary_idx = self.cursors.iter().position(|&c| c == primary).unwrap();
+        self.cursors.remove(primary_idx);
+        self.set_cursor(primary.0, primary.1);
+        self.set_cursors_dirty();
+    }
+
+    // Typed characters may change indentation or be auto-paired unlike characters of text inserted
+    // at once
     pub fn insert_char(&mut self, ch: char) {
+        self.each_cursor(|b| {
+            // Typing the closing character just before the same one steps over it
+            if b.pairs().iter().any(|&(_, close)| close == ch) && b.char_at_cursor() == Some(ch) {
+                b.cx += 1;
+                return;
+            }
+            if b.auto_indent && b.filetype.dedent_triggers().contains(&ch) {
+                b.dedent_before_closing();
+            }
+            match b.closing_pair(ch) {
+                Some(close) => b.insert_pair(ch, close),
+                None => b.insert_char_one(ch),
+            }
+        });
+    }
+
+    // Pairs inserted by typing their opening characters in this buffer
+    fn pairs(&self) -> &'static [(char, char)] {
+        if self.auto_pairs.enabled(self.filetype) {
+            self.filetype.pairs()
+        } else {
+            &[]
+        }
+    }
+
+    fn char_at_cursor(&self) -> Option<char> {
+        let row = self.row.get(self.cy)?;
+        (self.cx < row.len()).then(|| row.char_at(self.cx))
+    }
+
+    fn char_before_cursor(&self) -> Option<char> {
+        let row = self.row.get(self.cy)?;
+        (self.cx > 0).then(|| row.char_at(self.cx - 1))
+    }
+
+    // Closing character to insert with the typed one. Pairs are not inserted just before a word
+    // and quotes are not paired just after a word, e.g. `don't`, or after another quote.
+    fn closing_pair(&self, ch: char) -> Option<char> {
+        let &(open, close) = self.pairs().iter().find(|&&(open, _)| open == ch)?;
+        match self.char_at_cursor() {
+            Some(c) if !c.is_whitespace() && !matches!(c, ')' | ']' | '}') => return None,
+            _ => {}
+        }
+        if open == close {
+            match self.char_before_cursor() {
+                Some(c) if is_word_char(c) || c == open || c == '\\' => return None,
+                _ => {}
+            }
+        }
+        Some(close)
+    }
+
+    // Both characters are inserted as one undoable edit with the cursor between them
+    fn insert_pair(&mut self, open: char, close: char) {
+        self.insert_undo_point();
+        self.insert_char_one(open);
+        self.insert_char_one(close);
+        self.cx -= 1;
+    }
+
+    fn insert_char_one(&mut self, ch: char) {
         if self.cy == self.row.len() {
             self.new_diff(EditDiff::Newline);
         }
-        self.new_diff(EditDiff::InsertChar(self.cx, self.cy, ch));
+        let (x, y) = (self.cx, self.cy);
+        self.new_diff(EditDiff::InsertChar(x, y, ch));
+        self.map_cursors(|cx, cy| if cy == y && cx >= x { (cx + 1, cy) } else { (cx, cy) });
     }
 
     pub fn delete_right_char(&mut self) {
-        if self.cy == self.row.len()
-            || self.cy == self.row.len() - 1 && self.cx == self.row[self.cy].len() {
-            return;
-        }
-        self.move_cursor_one(CursorDir::Right);
-        self.delete_char();
+        self.each_cursor(|b| {
+            if b.cy == b.row.len()
+                || b.cy == b.row.len() - 1 && b.cx == b.row[b.cy].len() {
+                return;
+            }
+            b.move_cursor_one_at(CursorDir::Right);
+            b.delete_char_one();
+        });
     }
 
+    // Backspace in an empty pair such as `(|)` deletes both characters
     pub fn delete_char(&mut self) {
+        self.each_cursor(|b| {
+            let in_pair = match (b.char_before_cursor(), b.char_at_cursor()) {
+                (Some(open), Some(close)) => b.pairs().contains(&(open, close)),
+                _ => false,
+            };
+            if in_pair {
+                b.move_cursor_one_at(CursorDir::Right);
+                b.delete_char_one();
+            }
+            b.delete_char_one();
+        });
+    }
+
+    fn delete_char_one(&mut self) {
         if self.cy == self.row.len() || self.cx == 0 && self.cy == 0 {
             return;
         }
         self.insert_undo_point();
-        if self.cx > 0 {
-            let idx = self.cx - 1;
-            let deleted = self.row[self.cy].char_at(idx);
-            self.new_diff(EditDiff::DeleteChar(self.cx, self.cy, deleted));
+        let (x, y) = (self.cx, self.cy);
+        if x > 0 {
+            let idx = x - 1;
+            let deleted = self.row[y].char_at(idx);
+            self.new_diff(EditDiff::DeleteChar(x, y, deleted));
+            self.map_cursors(|cx, cy| if cy == y && cx >= x { (cx - 1, cy) } else { (cx, cy) });
         } else {
+            let prev_len = self.row[y - 1].len();
             self.squash_to_previous_line();
+            self.map_cursors(|cx, cy| match cy.cmp(&y) {
+                cmp::Ordering::Equal => (cx + prev_len, y - 1),
+                cmp::Ordering::Greater => (cx, cy - 1),
+                cmp::Ordering::Less => (cx, cy),
+            });
         }
     }
 
     pub fn insert_line(&mut self) {
+        self.each_cursor(|b| b.insert_line_one(b.auto_indent));
+    }
+
+    fn insert_line_one(&mut self, auto_indent: bool) {
         self.insert_undo_point();
+        let (x, y) = (self.cx, self.cy);
         if self.cy >= self.row.len() {
             self.new_diff(EditDiff::Newline);
-        } else if self.cx >= self.row[self.cy].len() {
-            self.new_diff(EditDiff::InsertLine(self.cy + 1, "".to_string()));
-        } else if self.cx <= self.row[self.cy].buffer().len() {
-            let truncated = self.row[self.cy][self.cx..].to_owned();
-            self.new_diff(EditDiff::Truncate(self.cy, truncated.clone()));
-            self.new_diff(EditDiff::InsertLine(self.cy + 1, truncated));
+            self.map_cursors(|cx, cy| if cy == y && cx >= x { (cx - x, y + 1) } else { (cx, cy) });
+            return;
+        }
+
+        let rest = self.row[y][x..].to_owned();
+        if !rest.is_empty() {
+            self.new_diff(EditDiff::Truncate(y, rest.clone()));
+        }
+        let (indent, extra) = if auto_indent {
+            self.indent_after(x, y)
+        } else {
+            (String::new(), String::new())
+        };
+        let text = if auto_indent { rest.trim_start() } else { rest.as_str() };
+        let trimmed = rest.chars().count() - text.chars().count();
+
+        // Between brackets such as `{|}`, the closing bracket goes to its own line below the
+        // indented line
+        let close = self.row[y][..x].trim_end().chars().last().and_then(filetype::closing_bracket);
+        let split_brackets = !extra.is_empty()
+            && matches!((close, text.chars().next()), (Some(a), Some(b)) if a == b);
+        let (new_x, lines) = if split_brackets {
+            let inner = format!("{}{}", indent, extra);
+            let len = inner.chars().count();
+            self.new_diff(EditDiff::InsertLine(y + 1, inner));
+            self.new_diff(EditDiff::InsertLine(y + 2, format!("{}{}", indent, text)));
+            (len, 2)
+        } else {
+            let line = format!("{}{}{}", indent, extra, text);
+            self.new_diff(EditDiff::InsertLine(y + 1, line));
+            (indent.chars().count() + extra.chars().count(), 1)
+        };
+        self.set_cursor(new_x, y + 1);
+
+        let shift = indent.chars().count() + if split_brackets { 0 } else { extra.chars().count() };
+        self.map_cursors(|cx, cy| {
+            if cy == y && cx >= x {
+                ((cx - x).saturating_sub(trimmed) + shift, y + lines)
+            } else if cy > y {
+                (cx, cy + lines)
+            } else {
+                (cx, cy)
+            }
+        });
+    }
+
+    // Indentation of the new line inserted at (x, y): the indentation copied from the line and an
+    // extra level when the text before the cursor ends with an opening bracket or similar
+    fn indent_after(&self, x: usize, y: usize) -> (String, String) {
+        let before = &self.row[y][..x];
+        let indent: String = before.chars().take_while(|&c| c == ' ' || c == '\t').collect();
+        let extra = match before.trim_end().chars().last() {
+            Some(c) if self.filetype.indent_triggers().contains(&c) => self.indent_unit(),
+            _ => String::new(),
+        };
+        (indent, extra)
+    }
+
+    // A closing bracket typed at the indentation of a line aligns the line with the line of the
+    // opening bracket
+    fn dedent_before_closing(&mut self) {
+        let (x, y) = (self.cx, self.cy);
+        if y >= self.row.len() {
+            return;
+        }
+        let before = &self.row[y][..x];
+        if before.is_empty() || !before.chars().all(|c| c == ' ' || c == '\t') {
+            return;
+        }
+        let (_, oy) = match self.unclosed_bracket_before(x, y) {
+            Some(pos) => pos,
+            None => return,
+        };
+        let indent: Vec<_> = self.row[oy].buffer().chars().take_while(|&c| c == ' ' || c == '\t').collect();
+        if self.row[y][..x].chars().eq(indent.iter().copied()) {
+            return;
         }
+
+        for _ in 0..x {
+            let c = self.row[y].char_at(0);
+            self.new_diff(EditDiff::DeleteChar(1, y, c));
+        }
+        for (i, &c) in indent.iter().enumerate() {
+            self.new_diff(EditDiff::InsertChar(i, y, c));
+        }
+        let len = indent.len();
+        self.set_cursor(len, y);
+        self.map_cursors(|cx, cy| if cy == y && cx >= x { (cx - x + len, cy) } else { (cx, cy) });
+    }
+
+    // Position of the innermost opening bracket which is not closed before (x, y)
+    fn unclosed_bracket_before(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        let mut depth = 0usize;
+        for cy in (0..=y).rev() {
+            let line = self.row[cy].buffer();
+            let chars: Vec<_> = if cy == y { line.chars().take(x).collect() } else { line.chars().collect() };
+            for (cx, &c) in chars.iter().enumerate().rev() {
+                if filetype::closing_bracket(c).is_some() {
+                    if depth == 0 {
+                        return Some((cx, cy));
+                    }
+                    depth -= 1;
+                } else if matches!(c, ')' | ']' | '}') {
+                    depth += 1;
+                }
+            }
+        }
+        None
     }
 
     pub fn move_cursor_one(&mut self, dir: CursorDir) {
+        if dir == CursorDir::Left || dir == CursorDir::Right {
+            self.each_cursor(|b| b.move_cursor_one_at(dir));
+            return;
+        }
+
+        let mut goals = vec![];
+        self.each_cursor(|b| {
+            let rx = b.row.get(b.cy).map(|row| row.rx_from_cx(b.cx)).unwrap_or(0);
+            let col = b.goal_col(rx);
+            b.move_cursor_one_at(dir);
+            if let Some(row) = b.row.get(b.cy) {
+                b.cx = row.cx_from_rx(col);
+            }
+            goals.push(((b.cx, b.cy), col));
+        });
+        self.goal_cols = goals;
+    }
+
+    pub fn clear_goal_col(&mut self) {
+        self.goal_cols.clear();
+    }
+
+    // Display column which a vertical move from the cursor keeps. `current` is the column of the
+    // cursor itself.
+    fn goal_col(&self, current: usize) -> usize {
+        self.goal_cols
+            .iter()
+            .find(|&&(pos, _)| pos == (self.cx, self.cy))
+            .map(|&(_, col)| col)
+            .unwrap_or(current)
+    }
+
+    fn move_cursor_one_at(&mut self, dir: CursorDir) {
         match dir {
             CursorDir::Up => self.cy = self.cy.saturating_sub(1),
             CursorDir::Left => {
@@ -265,6 +659,674 @@ impl TextBuffer {
         }
     }
 
+    // Moves cursors up or down by one visual line when lines are soft-wrapped at `width`
+    pub fn move_cursor_visual(&mut self, dir: CursorDir, width: usize) {
+        let mut goals = vec![];
+        self.each_cursor(|b| {
+            let col = b.move_cursor_visual_at(dir, width);
+            goals.push(((b.cx, b.cy), col));
+        });
+        self.goal_cols = goals;
+    }
+
+    // Returns the display column in visual lines which the move keeps
+    fn move_cursor_visual_at(&mut self, dir: CursorDir, width: usize) -> usize {
+        if self.cy >= self.row.len() {
+            let col = self.goal_col(0);
+            self.move_cursor_one_at(dir);
+            if let Some(row) = self.row.get(self.cy) {
+                let segments = row.wrap_segments(width);
+                let (start, end) = segments[segments.len() - 1];
+                let start_rx = row.rx_from_cx(start);
+                self.cx = cmp::min(row.cx_from_rx(start_rx + col), end);
+            }
+            return col;
+        }
+
+        let row = &self.row[self.cy];
+        let segments = row.wrap_segments(width);
+        let idx = segments
+            .iter()
+            .position(|&(_, e)| self.cx < e)
+            .unwrap_or(segments.len() - 1);
+        let col = self.goal_col(row.rx_from_cx(self.cx) - row.rx_from_cx(segments[idx].0));
+
+        let (y, segment, last) = match dir {
+            CursorDir::Up if idx > 0 => (self.cy, segments[idx - 1], false),
+            CursorDir::Up if self.cy > 0 => {
+                let segments = self.row[self.cy - 1].wrap_segments(width);
+                (self.cy - 1, segments[segments.len() - 1], true)
+            }
+            CursorDir::Down if idx + 1 < segments.len() => {
+                (self.cy, segments[idx + 1], idx + 2 == segments.len())
+            }
+            CursorDir::Down if self.cy + 1 < self.row.len() => {
+                let segments = self.row[self.cy + 1].wrap_segments(width);
+                (self.cy + 1, segments[0], segments.len() == 1)
+            }
+            _ => {
+                self.move_cursor_one_at(dir);
+                return col;
+            }
+        };
+
+        // Put the cursor at the same display column in the target visual line. Only the last
+        // visual line of a row can have the cursor at its end.
+        let row = &self.row[y];
+        let start_rx = row.rx_from_cx(segment.0);
+        let end = if last { segment.1 } else { segment.1.saturating_sub(1).max(segment.0) };
+        let mut x = segment.0;
+        while x < end && row.rx_from_cx(x + 1) - start_rx <= col {
+            x += 1;
+        }
+        self.set_cursor(x, y);
+        col
+    }
+
+    pub fn move_to_line_start(&mut self) {
+        self.each_cursor(|b| b.cx = 0);
+    }
+
+    pub fn move_to_line_end(&mut self) {
+        self.each_cursor(|b| b.cx = b.row.get(b.cy).map(Row::len).unwrap_or(0));
+    }
+
+    // Position just after the end of the next word, crossing lines as Emacs's forward-word does
+    fn next_word_end(&self, mut x: usize, mut y: usize) -> (usize, usize) {
+        let mut in_word = None;
+        while y < self.row.len() {
+            let row = &self.row[y];
+            while x < row.len() {
+                let kind = word_kind(row.char_at(x));
+                if in_word.is_some() && kind != in_word {
+                    return (x, y);
+                }
+                in_word = kind;
+                x += 1;
+            }
+            if in_word.is_some() || y + 1 == self.row.len() {
+                break;
+            }
+            y += 1;
+            x = 0;
+        }
+        (x, y)
+    }
+
+    // Position of the start of the previous word, crossing lines as Emacs's backward-word does
+    fn prev_word_start(&self, mut x: usize, mut y: usize) -> (usize, usize) {
+        if self.row.is_empty() {
+            return (x, y);
+        }
+        if y >= self.row.len() {
+            y = self.row.len() - 1;
+            x = self.row[y].len();
+        }
+        let mut in_word = None;
+        loop {
+            let row = &self.row[y];
+            while x > 0 {
+                let kind = word_kind(row.char_at(x - 1));
+                if in_word.is_some() && kind != in_word {
+                    return (x, y);
+                }
+                in_word = kind;
+                x -= 1;
+            }
+            if in_word.is_some() || y == 0 {
+                return (x, y);
+            }
+            y -= 1;
+            x = self.row[y].len();
+        }
+    }
+
+    pub fn move_word_forward(&mut self) {
+        self.each_cursor(|b| {
+            let (x, y) = b.next_word_end(b.cx, b.cy);
+            b.set_cursor(x, y);
+        });
+    }
+
+    pub fn move_word_backward(&mut self) {
+        self.each_cursor(|b| {
+            let (x, y) = b.prev_word_start(b.cx, b.cy);
+            b.set_cursor(x, y);
+        });
+    }
+
+    // Deletes text from the cursor to (ex, ey) and returns the deleted text. Lines in between are
+    // removed with `DeleteLine` so that undo restores them at once.
+    fn delete_until_one(&mut self, ex: usize, ey: usize) -> String {
+        let (sx, sy) = (self.cx, self.cy);
+        if (ey, ex) <= (sy, sx) || sy >= self.row.len() {
+            return String::new();
+        }
+        self.insert_undo_point();
+
+        let mut deleted = if sy == ey {
+            self.row[sy][sx..ex].to_owned()
+        } else {
+            self.row[sy][sx..].to_owned()
+        };
+        let tail = self.row[ey][ex..].to_owned();
+
+        let truncated = self.row[sy][sx..].to_owned();
+        if !truncated.is_empty() {
+            self.new_diff(EditDiff::Truncate(sy, truncated));
+        }
+        for y in sy + 1..=ey {
+            let line = self.row[sy + 1].buffer().to_owned();
+            deleted.push('\n');
+            if y == ey {
+                deleted.push_str(&self.row[sy + 1][..ex]);
+            }
+            self.new_diff(EditDiff::DeleteLine(sy + 1, line));
+        }
+        if !tail.is_empty() {
+            self.new_diff(EditDiff::Append(sy, tail));
+        }
+        self.set_cursor(sx, sy);
+
+        self.map_cursors(|cx, cy| {
+            if (cy, cx) <= (sy, sx) {
+                (cx, cy)
+            } else if (cy, cx) <= (ey, ex) {
+                (sx, sy)
+            } else if cy == ey {
+                (sx + cx - ex, sy)
+            } else {
+                (cx, cy - (ey - sy))
+            }
+        });
+        deleted
+    }
+
+    // Runs `f` at every cursor and returns texts deleted by it joined with newlines from top to
+    // bottom
+    fn kill_each_cursor<F: FnMut(&mut Self) -> String>(&mut self, mut f: F) -> String {
+        let mut killed = vec![];
+        self.each_cursor(|b| killed.push(f(b)));
+        killed.reverse();
+        killed.join("\n")
+    }
+
+    // Deletes the rest of the line, or the newline when the cursor is at the end of line
+    pub fn kill_line(&mut self) -> String {
+        self.kill_each_cursor(|b| {
+            let len = match b.row.get(b.cy) {
+                Some(row) => row.len(),
+                None => return String::new(),
+            };
+            if b.cx < len {
+                b.delete_until_one(len, b.cy)
+            } else if b.cy + 1 < b.row.len() {
+                b.delete_until_one(0, b.cy + 1)
+            } else {
+                String::new()
+            }
+        })
+    }
+
+    pub fn kill_word(&mut self) -> String {
+        self.kill_each_cursor(|b| {
+            let (x, y) = b.next_word_end(b.cx, b.cy);
+            b.delete_until_one(x, y)
+        })
+    }
+
+    pub fn kill_word_backward(&mut self) -> String {
+        self.kill_each_cursor(|b| {
+            // The cursor may be on the line after the last line
+            let (ex, ey) = match b.row.len().checked_sub(1) {
+                Some(last) if b.cy > last => (b.row[last].len(), last),
+                _ => (b.cx, b.cy),
+            };
+            let (x, y) = b.prev_word_start(ex, ey);
+            b.set_cursor(x, y);
+            b.delete_until_one(ex, ey)
+        })
+    }
+
+    fn insert_str_one(&mut self, text: &str) {
+        self.insert_undo_point();
+        for c in text.chars() {
+            if c == '\n' {
+                self.insert_line_one(false);
+            } else {
+                self.insert_char_one(c);
+            }
+        }
+    }
+
+    // Deletes the text between two positions with a single cursor and returns the deleted text
+    pub fn delete_range(&mut self, start: (usize, usize), end: (usize, usize)) -> String {
+        self.set_cursor(start.0, start.1);
+        self.delete_until_one(end.0, end.1)
+    }
+
+    // Inserts the text at the cursor and leaves the cursor after it
+    pub fn insert_str(&mut self, text: &str) {
+        self.insert_str_one(text);
+    }
+
+    // Inserts the text at every cursor. When the text has as many lines as cursors, such as text
+    // killed with multiple cursors, each cursor gets its own line.
+    pub fn yank(&mut self, text: &str) {
+        let lines: Vec<_> = text.split('\n').collect();
+        if self.has_multi_cursors() && lines.len() == self.cursors.len() + 1 {
+            // Cursors are visited from the bottom
+            let mut lines = lines.into_iter().rev();
+            self.each_cursor(|b| b.insert_str_one(lines.next().unwrap()));
+        } else {
+            self.each_cursor(|b| b.insert_str_one(text));
+        }
+    }
+
+    pub fn filetype(&self) -> FileType {
+        self.filetype
+    }
+
+    pub fn matching_bracket(&self) -> Option<BracketMatch> {
+        brackets::match_at(&self.row, self.filetype, (self.cx, self.cy))
+    }
+
+    pub fn version(&self) -> usize {
+        self.version
+    }
+
+    pub fn wrap(&self) -> bool {
+        self.wrap
+    }
+
+    pub fn toggle_wrap(&mut self) -> bool {
+        self.wrap = !self.wrap;
+        self.set_dirty_start(0);
+        self.wrap
+    }
+
+    fn set_tab_stop(&mut self, tab_stop: usize) {
+        if self.tab_stop != tab_stop {
+            self.tab_stop = tab_stop;
+            for row in self.row.iter_mut() {
+                row.set_tab_stop(tab_stop);
+            }
+            self.set_dirty_start(0);
+        }
+    }
+
+    fn set_wrap(&mut self, wrap: bool) {
+        if self.wrap != wrap {
+            self.wrap = wrap;
+            self.set_dirty_start(0);
+        }
+    }
+
+    pub fn apply_config(&mut self, config: &Config) {
+        self.set_tab_stop(config.tab_width);
+        self.set_wrap(config.wrap);
+        self.expand_tab = config.expand_tab;
+        self.auto_indent = config.auto_indent;
+        self.auto_pairs = config.auto_pairs.clone();
+        self.history.set_max_entries(config.undo_limit);
+    }
+
+    // Applies only the settings which differ from the previous configuration so that settings
+    // changed in this buffer, such as toggled soft-wrap, are kept
+    pub fn update_config(&mut self, prev: &Config, config: &Config) {
+        if prev.tab_width != config.tab_width {
+            self.set_tab_stop(config.tab_width);
+        }
+        if prev.wrap != config.wrap {
+            self.set_wrap(config.wrap);
+        }
+        if prev.expand_tab != config.expand_tab {
+            self.expand_tab = config.expand_tab;
+        }
+        if prev.auto_indent != config.auto_indent {
+            self.auto_indent = config.auto_indent;
+        }
+        if prev.auto_pairs != config.auto_pairs {
+            self.auto_pairs = config.auto_pairs.clone();
+        }
+        if prev.undo_limit != config.undo_limit {
+            self.history.set_max_entries(config.undo_limit);
+        }
+    }
+
+    pub fn toggle_expand_tab(&mut self) -> bool {
+        self.expand_tab = !self.expand_tab;
+        self.expand_tab
+    }
+
+    fn indent_unit(&self) -> String {
+        if self.expand_tab {
+            " ".repeat(self.tab_stop)
+        } else {
+            "\t".to_string()
+        }
+    }
+
+    // Lines covered by the selection. A selection ending at the start of a line doesn't include
+    // that line.
+    pub fn selected_lines(&self) -> Option<(usize, usize)> {
+        let ((_, sy), (ex, ey)) = self.selection()?;
+        let ey = if ey > sy && ex == 0 { ey - 1 } else { ey };
+        let last = self.row.len().checked_sub(1)?;
+        Some((cmp::min(sy, last), cmp::min(ey, last)))
+    }
+
+    // Runs an edit over whole lines while keeping the selection. `f` returns the column where
+    // characters were inserted (positive) or removed (negative) and how many.
+    fn edit_lines<F: FnMut(&mut Self, usize) -> (usize, isize)>(&mut self, sy: usize, ey: usize, mut f: F) {
+        self.insert_undo_point();
+        let mark = self.mark;
+        let (cx, cy) = (self.cx, self.cy);
+        let mut deltas = vec![];
+        for y in sy..=ey {
+            deltas.push(f(self, y));
+        }
+
+        let rows = &self.row;
+        let shift = |(x, y): (usize, usize)| {
+            if y < sy || ey < y {
+                return (x, y);
+            }
+            let (col, delta) = deltas[y - sy];
+            if x <= col {
+                return (x, y);
+            }
+            let x = (x as isize + delta).max(col as isize) as usize;
+            (cmp::min(x, rows[y].len()), y)
+        };
+        let (cx, cy) = shift((cx, cy));
+        let mark = mark.map(shift);
+        self.set_cursor(cx, cy);
+        self.mark = mark;
+        self.set_dirty_start(sy);
+    }
+
+    fn indent_line(&mut self, y: usize) -> isize {
+        if self.row[y].len() == 0 {
+            return 0;
+        }
+        let unit = self.indent_unit();
+        for (i, c) in unit.chars().enumerate() {
+            self.new_diff(EditDiff::InsertChar(i, y, c));
+        }
+        unit.chars().count() as isize
+    }
+
+    fn dedent_line(&mut self, y: usize) -> isize {
+        let removed = match self.row[y].char_at_checked(0) {
+            Some('\t') => 1,
+            Some(' ') => self.row[y]
+                .buffer()
+                .chars()
+                .take(self.tab_stop)
+                .take_while(|&c| c == ' ')
+                .count(),
+            _ => 0,
+        };
+        for _ in 0..removed {
+            let c = self.row[y].char_at(0);
+            self.new_diff(EditDiff::DeleteChar(1, y, c));
+        }
+        -(removed as isize)
+    }
+
+    // Tab key. Indents selected lines or inserts indentation up to the next tab stop at cursors
+    pub fn indent(&mut self) {
+        if let Some((sy, ey)) = self.selected_lines() {
+            self.edit_lines(sy, ey, |b, y| (0, b.indent_line(y)));
+            return;
+        }
+        self.each_cursor(|b| {
+            // Spaces are inserted up to the next tab stop
+            let text = match b.row.get(b.cy) {
+                Some(row) if b.expand_tab => " ".repeat(b.tab_stop - row.rx_from_cx(b.cx) % b.tab_stop),
+                _ => b.indent_unit(),
+            };
+            for c in text.chars() {
+                b.insert_char_one(c);
+            }
+        });
+    }
+
+    // Shift-Tab key. Dedents selected lines or the lines at cursors by one level
+    pub fn dedent(&mut self) {
+        if let Some((sy, ey)) = self.selected_lines() {
+            self.edit_lines(sy, ey, |b, y| (0, b.dedent_line(y)));
+            return;
+        }
+
+        // A line with several cursors is dedented only once
+        let mut lines: Vec<_> = self.cursors.iter().map(|&(_, y)| y).chain(Some(self.cy)).collect();
+        lines.retain(|&y| y < self.row.len());
+        lines.sort_unstable();
+        lines.dedup();
+
+        let (cx, cy) = (self.cx, self.cy);
+        self.insert_undo_point();
+        let removed: Vec<_> = lines.into_iter().map(|y| (y, -self.dedent_line(y) as usize)).collect();
+        self.set_cursor(cx, cy);
+        self.each_cursor(|b| {
+            if let Some(&(_, n)) = removed.iter().find(|&&(y, _)| y == b.cy) {
+                b.cx = b.cx.saturating_sub(n);
+            }
+        });
+    }
+
+    // Comments out the selected lines or the line at cursor with the line comment of the file type,
+    // or the block comment when the file type has no line comment. When all of the lines are
+    // already comments, they are uncommented instead. Blank lines are left as they are.
+    pub fn toggle_comment(&mut self) -> std::result::Result<(), &'static str> {
+        let (start, end) = match (self.filetype.line_comment(), self.filetype.block_comment()) {
+            (Some(line), _) => (line, None),
+            (None, Some((start, end))) => (start, Some(end)),
+            (None, None) => return Err("No comment syntax for this file type"),
+        };
+        let (sy, ey) = match self.selected_lines() {
+            Some(lines) => lines,
+            None if self.cy < self.row.len() => (self.cy, self.cy),
+            None => return Ok(()),
+        };
+
+        let indent_of = |line: &str| line.chars().take_while(|&c| c == ' ' || c == '\t').count();
+        let byte_idx = |line: &str, n: usize| line.char_indices().nth(n).map_or(line.len(), |(i, _)| i);
+        let lines: Vec<_> = (sy..=ey)
+            .map(|y| self.row[y].buffer())
+            .filter(|line| !line.trim().is_empty())
+            .collect();
+        let commented = !lines.is_empty()
+            && lines.iter().all(|line| {
+                let line = line.trim();
+                line.starts_with(start) && end.map(|end| line.ends_with(end)).unwrap_or(true)
+            });
+        // Markers are aligned at the smallest indentation of the lines
+        let col = match lines.iter().map(|line| indent_of(line)).min() {
+            Some(col) => col,
+            None => return Ok(()),
+        };
+
+        self.clear_multi_cursors();
+        self.edit_lines(sy, ey, |b, y| {
+            let line = b.row[y].buffer();
+            if line.trim().is_empty() {
+                return (0, 0);
+            }
+            let indent = indent_of(line);
+            let (head, body) = line.split_at(byte_idx(line, indent));
+            if commented {
+                let rest = body.strip_prefix(start).unwrap_or(body);
+                let rest = rest.strip_prefix(' ').unwrap_or(rest);
+                let removed = body.chars().count() - rest.chars().count();
+                let rest = match end {
+                    Some(end) => {
+                        let rest = rest.trim_end();
+                        let rest = rest.strip_suffix(end).unwrap_or(rest);
+                        rest.strip_suffix(' ').unwrap_or(rest)
+                    }
+                    None => rest,
+                };
+                b.replace_line(y, format!("{}{}", head, rest));
+                (indent, -(removed as isize))
+            } else {
+                let (pad, rest) = line.split_at(byte_idx(line, col));
+                let commented = match end {
+                    Some(end) => format!("{}{} {} {}", pad, start, rest, end),
+                    None => format!("{}{} {}", pad, start, rest),
+                };
+                b.replace_line(y, commented);
+                (col, start.chars().count() as isize + 1)
+            }
+        });
+        Ok(())
+    }
+
+    // The selected lines or the line at the primary cursor, which line commands work on
+    fn target_lines(&self) -> Option<(usize, usize)> {
+        match self.selected_lines() {
+            Some(lines) => Some(lines),
+            None if self.cy < self.row.len() => Some((self.cy, self.cy)),
+            None => None,
+        }
+    }
+
+    // Starts a line command. Secondary cursors are dropped but the selection is kept to be moved
+    // with the lines.
+    fn begin_line_command(&mut self) -> Option<(usize, usize)> {
+        let lines = self.target_lines()?;
+        self.insert_undo_point();
+        self.set_cursors_dirty();
+        self.cursors.clear();
+        Some(lines)
+    }
+
+    // Moves the cursor and the selection by `dy` lines after a line command
+    fn shift_cursor_lines(&mut self, mark: Option<(usize, usize)>, dy: isize) {
+        let shift = |(x, y): (usize, usize)| (x, (y as isize + dy) as usize);
+        let (cx, cy) = shift((self.cx, self.cy));
+        self.set_cursor(cx, cy);
+        self.mark = mark.map(shift);
+        self.set_cursors_dirty();
+    }
+
+    fn delete_line(&mut self, y: usize) -> String {
+        let line = self.row[y].buffer().to_owned();
+        self.new_diff(EditDiff::DeleteLine(y, line.clone()));
+        line
+    }
+
+    // Inserts a copy of the lines below them. The cursor and the selection move to the copy.
+    pub fn duplicate_lines(&mut self) {
+        let (sy, ey) = match self.begin_line_command() {
+            Some(lines) => lines,
+            None => return,
+        };
+        let mark = self.mark;
+        let (x, y) = (self.cx, self.cy);
+        let lines: Vec<_> = (sy..=ey).map(|y| self.row[y].buffer().to_owned()).collect();
+        for (i, line) in lines.into_iter().enumerate() {
+            self.new_diff(EditDiff::InsertLine(ey + 1 + i, line));
+        }
+        self.set_cursor(x, y);
+        self.shift_cursor_lines(mark, (ey - sy + 1) as isize);
+    }
+
+    pub fn delete_lines(&mut self) {
+        let (sy, ey) = match self.begin_line_command() {
+            Some(lines) => lines,
+            None => return,
+        };
+        self.mark = None;
+        let cx = self.cx;
+        for _ in sy..=ey {
+            self.delete_line(sy);
+        }
+        let y = cmp::min(sy, self.row.len());
+        let x = cmp::min(cx, self.row.get(y).map(Row::len).unwrap_or(0));
+        self.set_cursor(x, y);
+        self.set_dirty_start(sy);
+    }
+
+    // Swaps the lines with the line above or below them. Returns false at the top or the bottom.
+    pub fn move_lines(&mut self, dir: CursorDir) -> bool {
+        let (sy, ey) = match self.target_lines() {
+            Some((sy, _)) if dir == CursorDir::Up && sy == 0 => return false,
+            Some((_, ey)) if dir == CursorDir::Down && ey + 1 >= self.row.len() => return false,
+            Some(_) => self.begin_line_command().unwrap(),
+            None => return false,
+        };
+        let mark = self.mark;
+        let (x, y) = (self.cx, self.cy);
+        if dir == CursorDir::Up {
+            let line = self.delete_line(sy - 1);
+            self.new_diff(EditDiff::InsertLine(ey, line));
+        } else {
+            let line = self.delete_line(ey + 1);
+            self.new_diff(EditDiff::InsertLine(sy, line));
+        }
+        self.set_cursor(x, y);
+        self.shift_cursor_lines(mark, if dir == CursorDir::Up { -1 } else { 1 });
+        true
+    }
+
+    // Joins the selected lines, or the line at cursor and the next line, into one line. Whitespace
+    // around each joint is collapsed into a single space.
+    pub fn join_lines(&mut self) -> bool {
+        let (sy, ey) = match self.target_lines() {
+            Some((sy, ey)) if ey > sy => (sy, ey),
+            Some((sy, _)) if sy + 1 < self.row.len() => (sy, sy + 1),
+            _ => return false,
+        };
+        self.begin_line_command();
+        self.mark = None;
+
+        let mut joined = self.row[sy].buffer().trim_end().to_owned();
+        let mut joint = joined.chars().count();
+        for y in sy + 1..=ey {
+            let next = self.row[y].buffer().trim();
+            joint = joined.chars().count();
+            if !joined.is_empty() && !next.is_empty() {
+                joined.push(' ');
+            }
+            joined.push_str(next);
+        }
+        for _ in sy..=ey {
+            self.delete_line(sy);
+        }
+        self.new_diff(EditDiff::InsertLine(sy, joined));
+        // As Vim, the cursor is put at the last joint
+        self.set_cursor(joint, sy);
+        true
+    }
+
+    // Inserts an empty line below or above the lines and puts the cursor there. The new line is
+    // indented as the neighboring line when auto-indent is enabled.
+    pub fn open_line(&mut self, dir: CursorDir) {
+        let lines = self.target_lines();
+        self.insert_undo_point();
+        self.clear_multi_cursors();
+
+        let (y, indent) = match (lines, dir) {
+            (None, _) => (self.row.len(), String::new()),
+            (Some((sy, _)), CursorDir::Up) => {
+                let line = self.row[sy].buffer();
+                (sy, line.chars().take_while(|&c| c == ' ' || c == '\t').collect())
+            }
+            (Some((_, ey)), _) => {
+                let (indent, extra) = self.indent_after(self.row[ey].len(), ey);
+                (ey + 1, indent + &extra)
+            }
+        };
+        let indent = if self.auto_indent { indent } else { String::new() };
+        let x = indent.chars().count();
+        self.new_diff(EditDiff::InsertLine(y, indent));
+        self.set_cursor(x, y);
+        self.set_cursors_dirty();
+    }
+
     fn squash_to_previous_line(&mut self) {
         self.cy -= 1;
         self.cx = self.row[self.cy].len();
@@ -277,6 +1339,221 @@ impl TextBuffer {
         self.new_diff(EditDiff::Append(self.cy, removed));
     }
 
+    fn replace_line(&mut self, y: usize, line: String) {
+        let old = self.row[y].buffer().to_owned();
+        if !old.is_empty() {
+            self.new_diff(EditDiff::Truncate(y, old));
+        }
+        if !line.is_empty() {
+            self.new_diff(EditDiff::Append(y, line));
+        }
+    }
+
+    // Replaces lines from sy to ey with the lines of the text. Lines which are not changed are kept
+    // as they are.
+    pub fn replace_lines(&mut self, sy: usize, ey: usize, text: &str) {
+        self.insert_undo_point();
+        self.clear_multi_cursors();
+        let lines: Vec<_> = text.strip_suffix('\n').unwrap_or(text).split('\n').collect();
+        let old_len = ey - sy + 1;
+        for (i, line) in lines.iter().enumerate().take(old_len) {
+            if self.row[sy + i].buffer() != *line {
+                self.replace_line(sy + i, line.to_string());
+            }
+        }
+        for _ in lines.len()..old_len {
+            let y = sy + lines.len();
+            let removed = self.row[y].buffer().to_owned();
+            self.new_diff(EditDiff::DeleteLine(y, removed));
+        }
+        for (i, line) in lines.iter().enumerate().skip(old_len) {
+            self.new_diff(EditDiff::InsertLine(sy + i, line.to_string()));
+        }
+        self.set_cursor(0, sy);
+    }
+
+    // Replaces the pattern in lines from sy to ey. Only the first match in each line is replaced
+    // unless `global`. Returns the numbers of replaced matches and changed lines.
+    pub fn substitute(&mut self, sy: usize, ey: usize, pattern: &str, replacement: &str, global: bool) -> (usize, usize) {
+        self.insert_undo_point();
+        self.clear_multi_cursors();
+        let (mut matches, mut lines) = (0, 0);
+        let mut last = None;
+        for y in sy..cmp::min(ey + 1, self.row.len()) {
+            let line = self.row[y].buffer();
+            let (count, replaced) = if global {
+                (line.matches(pattern).count(), line.replace(pattern, replacement))
+            } else {
+                (usize::from(line.contains(pattern)), line.replacen(pattern, replacement, 1))
+            };
+            if count == 0 {
+                continue;
+            }
+            self.replace_line(y, replaced);
+            matches += count;
+            lines += 1;
+            last = Some(y);
+        }
+        // As Vim, the cursor moves to the last changed line
+        if let Some(y) = last {
+            self.set_cursor(0, y);
+        }
+        (matches, lines)
+    }
+
+    pub fn toggle_mark(&mut self) {
+        self.set_cursors_dirty();
+        self.mark = match self.mark {
+            Some(_) => None,
+            None => Some((self.cx, self.cy)),
+        };
+    }
+
+    pub fn selection(&self) -> Option<((usize, usize), (usize, usize))> {
+        let (mx, my) = self.mark?;
+        let (cx, cy) = (self.cx, self.cy);
+        if (my, mx) <= (cy, cx) {
+            Some(((mx, my), (cx, cy)))
+        } else {
+            Some(((cx, cy), (mx, my)))
+        }
+    }
+
+    pub fn secondary_cursors(&self) -> &[(usize, usize)] {
+        &self.cursors
+    }
+
+    pub fn has_multi_cursors(&self) -> bool {
+        !self.cursors.is_empty()
+    }
+
+    pub fn clear_multi_cursors(&mut self) -> bool {
+        if self.cursors.is_empty() && self.mark.is_none() {
+            return false;
+        }
+        self.set_cursors_dirty();
+        self.cursors.clear();
+        self.mark = None;
+        true
+    }
+
+    // The newly added cursor becomes the primary one so that the screen scrolls to follow it.
+    fn push_cursor(&mut self, x: usize, y: usize) {
+        if (x, y) == (self.cx, self.cy) || self.cursors.contains(&(x, y)) {
+            return;
+        }
+        self.set_cursors_dirty();
+        self.cursors.push((self.cx, self.cy));
+        self.set_cursor(x, y);
+        self.set_cursors_dirty();
+    }
+
+    fn word_at(&self, x: usize, y: usize) -> Option<(usize, usize)> {
+        let row = self.row.get(y)?;
+        let chars: Vec<char> = row.buffer().chars().collect();
+        let mut start = x;
+        while start > 0 && is_word_char(chars[start - 1]) {
+            start -= 1;
+        }
+        let mut end = x;
+        while end < chars.len() && is_word_char(chars[end]) {
+            end += 1;
+        }
+        if start == end {
+            None
+        } else {
+            Some((start, end))
+        }
+    }
+
+    fn word_matches_in_row(&self, y: usize, word: &str) -> Vec<usize> {
+        let chars: Vec<char> = self.row[y].buffer().chars().collect();
+        let word: Vec<char> = word.chars().collect();
+        let mut found = vec![];
+        if chars.len() < word.len() {
+            return found;
+        }
+        for x in 0..=chars.len() - word.len() {
+            if chars[x..x + word.len()] == word[..]
+                && (x == 0 || !is_word_char(chars[x - 1]))
+                && chars.get(x + word.len()).map(|&c| !is_word_char(c)).unwrap_or(true)
+            {
+                found.push(x);
+            }
+        }
+        found
+    }
+
+    pub fn add_cursor_at_next_match(&mut self) -> std::result::Result<(), &'static str> {
+        let (start, end) = self
+            .word_at(self.cx, self.cy)
+            .ok_or("No word under cursor")?;
+        let word = self.row[self.cy][start..end].to_owned();
+        let offset = self.cx - start;
+        let len = self.row.len();
+
+        for i in 0..=len {
+            let y = (self.cy + i) % len;
+            for x in self.word_matches_in_row(y, &word) {
+                if i == 0 && x <= start || i == len && x >= start {
+                    continue;
+                }
+                let pos = (x + offset, y);
+                if !self.cursors.contains(&pos) {
+                    self.push_cursor(pos.0, pos.1);
+                    return Ok(());
+                }
+            }
+        }
+        Err("No more matches")
+    }
+
+    pub fn add_cursor_vertically(&mut self, dir: CursorDir) -> bool {
+        let (x, y) = (self.cx, self.cy);
+        let y = match dir {
+            CursorDir::Up if y > 0 => y - 1,
+            CursorDir::Down if y + 1 < self.row.len() => y + 1,
+            _ => return false,
+        };
+        let x = cmp::min(x, self.row[y].len());
+        self.push_cursor(x, y);
+        true
+    }
+
+    pub fn split_selection_into_lines(&mut self) -> bool {
+        let ((_, sy), (ex, ey)) = match self.selection() {
+            Some(s) => s,
+            None => return false,
+        };
+        self.set_cursors_dirty();
+        self.mark = None;
+        self.cursors = (sy..ey)
+            .filter(|&y| y < self.row.len())
+            .map(|y| (self.row[y].len(), y))
+            .collect();
+        let ex = cmp::min(ex, self.row.get(ey).map(Row::len).unwrap_or(0));
+        self.set_cursor(ex, ey);
+        self.set_cursors_dirty();
+        true
+    }
+
+    // Puts the cursor back to a position saved earlier. The buffer may have been edited since
+    // then, so the position is clamped into the buffer.
+    pub fn restore_cursor(&mut self, x: usize, y: usize) {
+        let y = cmp::min(y, self.row.len().saturating_sub(1));
+        let x = cmp::min(x, self.row.get(y).map(Row::len).unwrap_or(0));
+        self.set_cursor(x, y);
+        self.goal_cols.clear();
+    }
+
+    // Moves the cursor keeping the selection and redrawing it
+    pub fn jump_to(&mut self, x: usize, y: usize) {
+        self.set_cursors_dirty();
+        self.set_cursor(x, y);
+        self.set_cursors_dirty();
+        self.goal_cols.clear();
+    }
+
     pub fn set_cursor(&mut self, x: usize, y: usize) {
         self.cx = x;
         self.cy = y;
@@ -291,7 +1568,7 @@ impl TextBuffer {
     }
 
     pub fn undo(&mut self) -> bool {
-        let state = self.history.undo(&mut self.row);
+        let state = self.history.undo(&mut self.row, self.tab_stop);
         if let Some((_, _, _, edited)) = state {
             if !edited {
                 self.undo_count = self.undo_count.saturating_sub(1);
@@ -302,7 +1579,7 @@ impl TextBuffer {
     }
 
     pub fn redo(&mut self) -> bool {
-        let state = self.history.redo(&mut self.row);
+        let state = self.history.redo(&mut self.row, self.tab_stop);
         if let Some((_, _, _, edited)) = state {
             if !edited {
                 self.undo_count = self.undo_count.saturating_add(1);
@@ -315,6 +1592,7 @@ impl TextBuffer {
     fn after_undoredo(&mut self, state: Option<(usize, usize, usize, bool)>) -> bool {
         match state {
             Some((x, y, s, _)) => {
+                self.clear_multi_cursors();
                 self.set_cursor(x, y);
                 self.set_dirty_start(s);
                 true
@@ -0,0 +1,797 @@
+use crate::brackets::{self, BracketMatch};
+use crate::filetype::FileType;
+use crate::input::{InputSeq, KeySeq};
+use crate::row::Row;
+use crate::text_buffer::{word_kind, TextBuffer, WordKind};
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Mode {
+    Normal,
+    Insert,
+    Visual,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Visual => "VISUAL",
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    LineEnd,
+    FirstLine,
+    LastLine,
+    FindChar(char),
+    MatchingBracket,
+}
+
+#[derive(PartialEq)]
+enum MotionKind {
+    // The character at the end of motion is not included in the range of operator
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+impl Motion {
+    fn kind(self) -> MotionKind {
+        use Motion::*;
+        match self {
+            Left | Right | WordForward | WordBackward | LineStart => MotionKind::Exclusive,
+            WordEnd | LineEnd | FindChar(_) | MatchingBracket => MotionKind::Inclusive,
+            Up | Down | FirstLine | LastLine => MotionKind::Linewise,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum InsertAt {
+    Cursor,
+    AfterCursor,
+    LineStart,
+    LineEnd,
+    LineBelow,
+    LineAbove,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Action {
+    Move(Motion),
+    Operate(Operator, Motion),
+    // Operator doubled such as `dd`, or operator in visual mode
+    OperateLines(Operator),
+    OperateSelection(Operator),
+    DeleteChar,
+    Insert(InsertAt),
+    Paste { before: bool },
+    Undo,
+    Redo,
+    Repeat,
+    // `:` opens the command line
+    CommandLine,
+    ToggleVisual,
+    Escape,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct ViCommand {
+    pub count: Option<usize>,
+    pub action: Action,
+}
+
+impl ViCommand {
+    // Commands repeated by `.`
+    fn is_change(&self) -> bool {
+        use Action::*;
+        match self.action {
+            Operate(op, _) | OperateLines(op) => op != Operator::Yank,
+            DeleteChar | Insert(_) | Paste { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+pub enum Feed {
+    Pending,
+    Invalid,
+    Command(ViCommand),
+    // The key is not handled in vi's grammar and should be looked up in the keymap
+    NotVi,
+}
+
+enum Parse<T> {
+    Done(T, usize),
+    Incomplete,
+    Invalid,
+}
+
+fn char_of(seq: &InputSeq) -> Option<char> {
+    match seq {
+        InputSeq { ctrl: true, .. } | InputSeq { alt: true, .. } => None,
+        InputSeq { key: KeySeq::Key(b), .. } if !b.is_ascii_control() => Some(*b as char),
+        InputSeq { key: KeySeq::Utf8Key(c), .. } => Some(*c),
+        _ => None,
+    }
+}
+
+fn parse_count(keys: &[InputSeq], mut i: usize) -> (Option<usize>, usize) {
+    let mut count = None;
+    while let Some(c) = keys.get(i).and_then(char_of) {
+        match c.to_digit(10) {
+            // Leading '0' is a motion to the line start
+            Some(0) if count.is_none() => break,
+            Some(d) => count = Some(count.unwrap_or(0) * 10 + d as usize),
+            None => break,
+        }
+        i += 1;
+    }
+    (count, i)
+}
+
+fn parse_motion(keys: &[InputSeq], i: usize) -> Parse<Motion> {
+    use KeySeq::*;
+    use Motion::*;
+
+    let seq = match keys.get(i) {
+        Some(seq) => seq,
+        None => return Parse::Incomplete,
+    };
+    let motion = match (&seq.key, seq.ctrl, seq.alt) {
+        (UpKey, false, false) => Up,
+        (DownKey, false, false) => Down,
+        (LeftKey, false, false) => Left,
+        (RightKey, false, false) => Right,
+        (Key(b'm'), true, false) => Down,
+        (Key(b'h'), true, false) | (Key(0x7f), false, false) => Left,
+        _ => match char_of(seq) {
+            Some('h') => Left,
+            Some('l') | Some(' ') => Right,
+            Some('j') => Down,
+            Some('k') => Up,
+            Some('w') => WordForward,
+            Some('b') => WordBackward,
+            Some('e') => WordEnd,
+            Some('0') => LineStart,
+            Some('$') => LineEnd,
+            Some('G') => LastLine,
+            Some('%') => MatchingBracket,
+            Some('g') => {
+                return match keys.get(i + 1).map(char_of) {
+                    None => Parse::Incomplete,
+                    Some(Some('g')) => Parse::Done(FirstLine, i + 2),
+                    Some(_) => Parse::Invalid,
+                }
+            }
+            Some('f') => {
+                return match keys.get(i + 1).map(char_of) {
+                    None => Parse::Incomplete,
+                    Some(Some(c)) => Parse::Done(FindChar(c), i + 2),
+                    Some(None) => Parse::Invalid,
+                }
+            }
+            _ => return Parse::Invalid,
+        },
+    };
+    Parse::Done(motion, i + 1)
+}
+
+// Parses keys typed in normal or visual mode. A command is `[count] motion`,
+// `[count] operator [count] motion`, `[count] operator operator` or `[count] command`.
+fn parse(keys: &[InputSeq], mode: Mode) -> Parse<ViCommand> {
+    use Action::*;
+
+    let (count, i) = parse_count(keys, 0);
+    let seq = match keys.get(i) {
+        Some(seq) => seq,
+        None => return Parse::Incomplete,
+    };
+    let done = |action, end| Parse::Done(ViCommand { count, action }, end);
+
+    if seq.ctrl && seq.key == KeySeq::Key(b'r') {
+        return done(Redo, i + 1);
+    }
+    if *seq == InputSeq::new(KeySeq::Key(0x1b)) {
+        return done(Escape, i + 1);
+    }
+
+    let operator = match char_of(seq) {
+        Some('d') => Some(Operator::Delete),
+        Some('c') => Some(Operator::Change),
+        Some('y') => Some(Operator::Yank),
+        _ => None,
+    };
+    if let Some(op) = operator {
+        if mode == Mode::Visual {
+            return done(OperateSelection(op), i + 1);
+        }
+        let (count2, j) = parse_count(keys, i + 1);
+        let count = match (count, count2) {
+            (Some(a), Some(b)) => Some(a * b),
+            (a, b) => a.or(b),
+        };
+        if keys.get(j).and_then(char_of) == char_of(seq) {
+            return Parse::Done(ViCommand { count, action: OperateLines(op) }, j + 1);
+        }
+        return match parse_motion(keys, j) {
+            Parse::Done(m, end) => Parse::Done(ViCommand { count, action: Operate(op, m) }, end),
+            Parse::Incomplete => Parse::Incomplete,
+            Parse::Invalid => Parse::Invalid,
+        };
+    }
+
+    let action = match char_of(seq) {
+        Some('x') if mode == Mode::Visual => OperateSelection(Operator::Delete),
+        Some('x') => DeleteChar,
+        Some('i') => Insert(InsertAt::Cursor),
+        Some('a') => Insert(InsertAt::AfterCursor),
+        Some('I') => Insert(InsertAt::LineStart),
+        Some('A') => Insert(InsertAt::LineEnd),
+        Some('o') => Insert(InsertAt::LineBelow),
+        Some('O') => Insert(InsertAt::LineAbove),
+        Some('p') => Paste { before: false },
+        Some('P') => Paste { before: true },
+        Some('u') => Undo,
+        Some('.') => Repeat,
+        Some(':') => CommandLine,
+        Some('v') => ToggleVisual,
+        _ => {
+            return match parse_motion(keys, i) {
+                Parse::Done(m, end) => done(Move(m), end),
+                Parse::Incomplete => Parse::Incomplete,
+                Parse::Invalid => Parse::Invalid,
+            }
+        }
+    };
+    done(action, i + 1)
+}
+
+fn line_len(rows: &[Row], y: usize) -> usize {
+    rows.get(y).map(Row::len).unwrap_or(0)
+}
+
+fn char_at(rows: &[Row], (x, y): (usize, usize)) -> Option<char> {
+    rows.get(y)?.char_at_checked(x)
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum Class {
+    // Including the end of line
+    Blank,
+    // Word characters of the same script make a word
+    Word(WordKind),
+    Punct,
+}
+
+fn class(c: Option<char>) -> Class {
+    match c {
+        None => Class::Blank,
+        Some(c) if c.is_whitespace() => Class::Blank,
+        Some(c) => word_kind(c).map(Class::Word).unwrap_or(Class::Punct),
+    }
+}
+
+// Positions run through every character and the end of each line
+fn next_pos(rows: &[Row], (x, y): (usize, usize)) -> Option<(usize, usize)> {
+    if x < line_len(rows, y) {
+        Some((x + 1, y))
+    } else if y + 1 < rows.len() {
+        Some((0, y + 1))
+    } else {
+        None
+    }
+}
+
+fn prev_pos(rows: &[Row], (x, y): (usize, usize)) -> Option<(usize, usize)> {
+    if x > 0 {
+        Some((x - 1, y))
+    } else if y > 0 {
+        Some((line_len(rows, y - 1), y - 1))
+    } else {
+        None
+    }
+}
+
+// An empty line is regarded as a word
+fn is_empty_line(rows: &[Row], (x, y): (usize, usize)) -> bool {
+    x == 0 && line_len(rows, y) == 0
+}
+
+fn word_forward(rows: &[Row], start: (usize, usize)) -> (usize, usize) {
+    let mut p = start;
+    let c = class(char_at(rows, p));
+    if c != Class::Blank {
+        while class(char_at(rows, p)) == c {
+            match next_pos(rows, p) {
+                Some(n) => p = n,
+                None => return p,
+            }
+        }
+    }
+    while class(char_at(rows, p)) == Class::Blank && !(p != start && is_empty_line(rows, p)) {
+        match next_pos(rows, p) {
+            Some(n) => p = n,
+            None => return p,
+        }
+    }
+    p
+}
+
+fn word_backward(rows: &[Row], start: (usize, usize)) -> (usize, usize) {
+    let mut p = match prev_pos(rows, start) {
+        Some(p) => p,
+        None => return start,
+    };
+    while class(char_at(rows, p)) == Class::Blank && !is_empty_line(rows, p) {
+        match prev_pos(rows, p) {
+            Some(n) => p = n,
+            None => return p,
+        }
+    }
+    let c = class(char_at(rows, p));
+    if c != Class::Blank {
+        while let Some(q) = prev_pos(rows, p) {
+            if class(char_at(rows, q)) != c {
+                break;
+            }
+            p = q;
+        }
+    }
+    p
+}
+
+fn word_end(rows: &[Row], start: (usize, usize)) -> (usize, usize) {
+    let mut p = match next_pos(rows, start) {
+        Some(p) => p,
+        None => return start,
+    };
+    while class(char_at(rows, p)) == Class::Blank {
+        match next_pos(rows, p) {
+            Some(n) => p = n,
+            None => return p,
+        }
+    }
+    let c = class(char_at(rows, p));
+    while let Some(q) = next_pos(rows, p) {
+        if class(char_at(rows, q)) != c {
+            break;
+        }
+        p = q;
+    }
+    p
+}
+
+type Pos = (usize, usize);
+
+fn first_non_blank(rows: &[Row], y: usize) -> usize {
+    rows.get(y)
+        .map(|row| row.buffer().chars().take_while(|c| c.is_whitespace()).count())
+        .unwrap_or(0)
+}
+
+// Where the motion moves the cursor. `None` means the motion failed.
+fn target(
+    rows: &[Row],
+    filetype: FileType,
+    (x, y): (usize, usize),
+    motion: Motion,
+    count: Option<usize>,
+) -> Option<(usize, usize)> {
+    use Motion::*;
+
+    let n = count.unwrap_or(1);
+    let last_line = rows.len().saturating_sub(1);
+    let repeat = |f: fn(&[Row], Pos) -> Pos| {
+        (0..n).fold((x, y), |p, _| f(rows, p))
+    };
+
+    let pos = match motion {
+        Left => (x.saturating_sub(n), y),
+        Right => (std::cmp::min(x + n, line_len(rows, y)), y),
+        Up | Down => {
+            let y = if motion == Up { y.saturating_sub(n) } else { std::cmp::min(y + n, last_line) };
+            (std::cmp::min(x, line_len(rows, y)), y)
+        }
+        WordForward => repeat(word_forward),
+        WordBackward => repeat(word_backward),
+        WordEnd => repeat(word_end),
+        LineStart => (0, y),
+        LineEnd => {
+            let y = std::cmp::min(y + n - 1, last_line);
+            (line_len(rows, y).saturating_sub(1), y)
+        }
+        FirstLine | LastLine => {
+            let y = match count {
+                Some(n) => std::cmp::min(n - 1, last_line),
+                None if motion == FirstLine => 0,
+                None => last_line,
+            };
+            (first_non_blank(rows, y), y)
+        }
+        FindChar(c) => {
+            let row = rows.get(y)?;
+            let found = (x + 1..row.len()).filter(|&i| row.char_at(i) == c).nth(n - 1)?;
+            (found, y)
+        }
+        // As Vim, the first bracket from the cursor in the line is matched
+        MatchingBracket => {
+            let row = rows.get(y)?;
+            let x = (x..row.len()).find(|&i| "()[]{}".contains(row.char_at(i)))?;
+            match brackets::match_at(rows, filetype, (x, y))? {
+                BracketMatch::Matched(_, partner) => partner,
+                BracketMatch::Unmatched(_) => return None,
+            }
+        }
+    };
+    Some(pos)
+}
+
+fn text_between(rows: &[Row], (sx, sy): (usize, usize), (ex, ey): (usize, usize)) -> String {
+    if sy == ey {
+        return rows[sy][sx..ex].to_owned();
+    }
+    let mut text = rows[sy][sx..].to_owned();
+    for row in &rows[sy + 1..ey] {
+        text.push('\n');
+        text.push_str(row.buffer());
+    }
+    text.push('\n');
+    text.push_str(&rows[ey][..ex]);
+    text
+}
+
+// The position just after the character at `(x, y)`, which is the end of an inclusive range
+fn after(rows: &[Row], (x, y): (usize, usize)) -> (usize, usize) {
+    if x < line_len(rows, y) {
+        (x + 1, y)
+    } else {
+        next_pos(rows, (x, y)).unwrap_or((x, y))
+    }
+}
+
+pub struct Vi {
+    pub mode: Mode,
+    keys: Vec<InputSeq>,
+    register: String,
+    // Text in register is whole lines such as text yanked by `yy`
+    linewise: bool,
+    last_change: Option<ViCommand>,
+    // Keys typed in insert mode started by the last change, replayed by `.`
+    inserted: Vec<InputSeq>,
+}
+
+impl Default for Vi {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Normal,
+            keys: vec![],
+            register: String::new(),
+            linewise: false,
+            last_change: None,
+            inserted: vec![],
+        }
+    }
+}
+
+impl Vi {
+    pub fn feed(&mut self, seq: InputSeq) -> Feed {
+        if self.keys.is_empty() && (seq.ctrl || seq.alt) && !matches!(seq.key, KeySeq::Key(b'r' | b'm' | b'h')) {
+            return Feed::NotVi;
+        }
+        self.keys.push(seq);
+        match parse(&self.keys, self.mode) {
+            Parse::Done(cmd, _) => {
+                self.keys.clear();
+                Feed::Command(cmd)
+            }
+            Parse::Incomplete => Feed::Pending,
+            Parse::Invalid => {
+                self.keys.clear();
+                Feed::Invalid
+            }
+        }
+    }
+
+    pub fn record_insert(&mut self, seq: InputSeq) {
+        self.inserted.push(seq);
+    }
+
+    // `.` repeats the last change with the keys typed in insert mode after it. A count given to
+    // `.` replaces the original count.
+    pub fn repeat(&self, count: Option<usize>) -> Option<(ViCommand, Vec<InputSeq>)> {
+        let mut cmd = self.last_change?;
+        if count.is_some() {
+            cmd.count = count;
+        }
+        Some((cmd, self.inserted.clone()))
+    }
+
+    fn enter_insert(&mut self) {
+        self.mode = Mode::Insert;
+        self.inserted.clear();
+    }
+
+    pub fn leave_insert(&mut self, buf: &mut TextBuffer) {
+        buf.end_undo_group();
+        self.mode = Mode::Normal;
+        // As Vim, the cursor moves back onto the last inserted character
+        let (x, y) = buf.cursor();
+        buf.set_cursor(x.saturating_sub(1), y);
+        self.clamp_cursor(buf);
+    }
+
+    fn enter_visual(&mut self, buf: &mut TextBuffer) {
+        if buf.selection().is_none() {
+            buf.toggle_mark();
+        }
+        self.mode = Mode::Visual;
+    }
+
+    fn leave_visual(&mut self, buf: &mut TextBuffer) {
+        if buf.selection().is_some() {
+            buf.toggle_mark();
+        }
+        self.mode = Mode::Normal;
+    }
+
+    // The cursor is on a character in normal mode, not after the end of line
+    fn clamp_cursor(&self, buf: &mut TextBuffer) {
+        if self.mode == Mode::Insert {
+            return;
+        }
+        let rows = buf.rows();
+        let (x, y) = buf.cursor();
+        let y = std::cmp::min(y, rows.len().saturating_sub(1));
+        let x = std::cmp::min(x, line_len(rows, y).saturating_sub(1));
+        buf.set_cursor(x, y);
+    }
+
+    fn set_register(&mut self, text: String, linewise: bool) {
+        self.register = text;
+        self.linewise = linewise;
+    }
+
+    // Applies the operator to the text between `start` and `end`, or to the lines between them
+    fn operate(&mut self, buf: &mut TextBuffer, op: Operator, start: (usize, usize), end: (usize, usize), linewise: bool) {
+        if linewise {
+            let (sy, ey) = (start.1, std::cmp::min(end.1, buf.rows().len().saturating_sub(1)));
+            let last = line_len(buf.rows(), ey);
+            let mut text = text_between(buf.rows(), (0, sy), (last, ey));
+            text.push('\n');
+            self.set_register(text, true);
+            match op {
+                Operator::Yank => buf.set_cursor(first_non_blank(buf.rows(), sy), sy),
+                Operator::Delete => {
+                    let len = buf.rows().len();
+                    if ey + 1 < len {
+                        buf.delete_range((0, sy), (0, ey + 1));
+                    } else if sy > 0 {
+                        buf.delete_range((line_len(buf.rows(), sy - 1), sy - 1), (last, ey));
+                    } else {
+                        buf.delete_range((0, 0), (last, ey));
+                    }
+                    let y = std::cmp::min(sy, buf.rows().len().saturating_sub(1));
+                    buf.set_cursor(first_non_blank(buf.rows(), y), y);
+                }
+                Operator::Change => {
+                    buf.delete_range((0, sy), (last, ey));
+                    self.enter_insert();
+                }
+            }
+            return;
+        }
+
+        let text = text_between(buf.rows(), start, end);
+        self.set_register(text, false);
+        match op {
+            Operator::Yank => buf.set_cursor(start.0, start.1),
+            Operator::Delete => {
+                buf.delete_range(start, end);
+            }
+            Operator::Change => {
+                buf.delete_range(start, end);
+                self.enter_insert();
+            }
+        }
+    }
+
+    fn operate_motion(&mut self, buf: &mut TextBuffer, op: Operator, motion: Motion, count: Option<usize>) -> bool {
+        let cursor = buf.cursor();
+        let rows = buf.rows();
+        // `cw` on a word works as `ce` as Vim does
+        let motion = if op == Operator::Change && motion == Motion::WordForward && class(char_at(rows, cursor)) != Class::Blank {
+            Motion::WordEnd
+        } else {
+            motion
+        };
+        let mut to = match target(rows, buf.filetype(), cursor, motion, count) {
+            Some(to) => to,
+            None => return false,
+        };
+        // `dw` at the last word of a line does not join the next line
+        if motion == Motion::WordForward && to.1 > cursor.1 {
+            to = (line_len(rows, cursor.1), cursor.1);
+        }
+
+        let (start, end) = if (to.1, to.0) < (cursor.1, cursor.0) { (to, cursor) } else { (cursor, to) };
+        match motion.kind() {
+            MotionKind::Linewise => self.operate(buf, op, start, end, true),
+            MotionKind::Inclusive => {
+                let end = after(buf.rows(), end);
+                self.operate(buf, op, start, end, false);
+            }
+            MotionKind::Exclusive => self.operate(buf, op, start, end, false),
+        }
+        true
+    }
+
+    fn paste(&mut self, buf: &mut TextBuffer, before: bool, count: usize) {
+        let (x, y) = buf.cursor();
+        if self.linewise {
+            let text = self.register.repeat(count);
+            if before {
+                buf.set_cursor(0, y);
+                buf.insert_str(&text);
+                buf.set_cursor(first_non_blank(buf.rows(), y), y);
+            } else {
+                buf.set_cursor(line_len(buf.rows(), y), y);
+                buf.insert_str(&format!("\n{}", text.strip_suffix('\n').unwrap_or(&text)));
+                buf.set_cursor(first_non_blank(buf.rows(), y + 1), y + 1);
+            }
+        } else {
+            let x = if before { x } else { std::cmp::min(x + 1, line_len(buf.rows(), y)) };
+            buf.set_cursor(x, y);
+            buf.insert_str(&self.register.repeat(count));
+            let (x, y) = buf.cursor();
+            buf.set_cursor(x.saturating_sub(1), y);
+        }
+    }
+
+    fn insert(&mut self, buf: &mut TextBuffer, at: InsertAt) {
+        let (x, y) = buf.cursor();
+        let len = line_len(buf.rows(), y);
+        match at {
+            InsertAt::Cursor => {}
+            InsertAt::AfterCursor => buf.set_cursor(std::cmp::min(x + 1, len), y),
+            InsertAt::LineStart => buf.set_cursor(first_non_blank(buf.rows(), y), y),
+            InsertAt::LineEnd => buf.set_cursor(len, y),
+            // Indented as a newline typed at the end of the line
+            InsertAt::LineBelow => {
+                buf.set_cursor(len, y);
+                buf.insert_line();
+            }
+            InsertAt::LineAbove => {
+                buf.set_cursor(0, y);
+                buf.insert_str("\n");
+                buf.set_cursor(0, y);
+            }
+        }
+        self.enter_insert();
+    }
+
+    // Runs the command except for `.`, which needs to replay keys through the editor
+    pub fn execute(&mut self, buf: &mut TextBuffer, cmd: ViCommand) -> Result<(), &'static str> {
+        use Action::*;
+
+        let count = cmd.count.unwrap_or(1);
+        let mut grouped = cmd.is_change();
+        if grouped {
+            self.last_change = Some(cmd);
+            buf.begin_undo_group();
+        }
+
+        let result = match cmd.action {
+            Move(motion) => match target(buf.rows(), buf.filetype(), buf.cursor(), motion, cmd.count) {
+                Some((x, y)) => {
+                    buf.jump_to(x, y);
+                    Ok(())
+                }
+                None => Err("Motion failed"),
+            },
+            Operate(op, motion) => {
+                if self.operate_motion(buf, op, motion, cmd.count) {
+                    Ok(())
+                } else {
+                    Err("Motion failed")
+                }
+            }
+            OperateLines(op) => {
+                let y = buf.cursor().1;
+                self.operate(buf, op, (0, y), (0, y + count - 1), true);
+                Ok(())
+            }
+            OperateSelection(op) => {
+                let (start, end) = match buf.selection() {
+                    Some((start, end)) => (start, after(buf.rows(), end)),
+                    None => (buf.cursor(), buf.cursor()),
+                };
+                self.mode = Mode::Normal;
+                if op != Operator::Yank {
+                    grouped = true;
+                    buf.begin_undo_group();
+                }
+                self.operate(buf, op, start, end, false);
+                if buf.selection().is_some() {
+                    buf.toggle_mark();
+                }
+                Ok(())
+            }
+            DeleteChar => {
+                let (x, y) = buf.cursor();
+                let end = std::cmp::min(x + count, line_len(buf.rows(), y));
+                if x < end {
+                    self.operate(buf, Operator::Delete, (x, y), (end, y), false);
+                }
+                Ok(())
+            }
+            Insert(at) => {
+                self.insert(buf, at);
+                Ok(())
+            }
+            Paste { before } => {
+                self.paste(buf, before, count);
+                Ok(())
+            }
+            Undo => {
+                if buf.undo() {
+                    Ok(())
+                } else {
+                    Err("Already at oldest change")
+                }
+            }
+            Redo => {
+                if buf.redo() {
+                    Ok(())
+                } else {
+                    Err("Already at newest change")
+                }
+            }
+            ToggleVisual => {
+                if self.mode == Mode::Visual {
+                    self.leave_visual(buf);
+                } else {
+                    self.enter_visual(buf);
+                }
+                Ok(())
+            }
+            Escape => {
+                if self.mode == Mode::Visual {
+                    self.leave_visual(buf);
+                } else {
+                    buf.clear_multi_cursors();
+                }
+                Ok(())
+            }
+            // Run by the editor
+            Repeat | CommandLine => Ok(()),
+        };
+
+        // The group of a change entering insert mode is ended by `leave_insert`
+        if self.mode != Mode::Insert {
+            if grouped {
+                buf.end_undo_group();
+            }
+            self.clamp_cursor(buf);
+        }
+        result
+    }
+}
@@ -0,0 +1,661 @@
+use crate::gutter::Gutter;
+use crate::row::Row;
+use crate::status_bar::StatusBar;
+
+use std::cmp;
+use unicode_width::UnicodeWidthChar;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum SplitDir {
+    // Windows are stacked from top to bottom
+    Horizontal,
+    // Windows are placed side by side from left to right
+    Vertical,
+}
+
+#[derive(PartialEq, Clone, Copy, Default, Debug)]
+pub struct Rect {
+    pub top: usize,
+    pub left: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    fn len(&self, dir: SplitDir) -> usize {
+        match dir {
+            SplitDir::Horizontal => self.height,
+            SplitDir::Vertical => self.width,
+        }
+    }
+}
+
+pub struct Window {
+    pub buf_idx: usize,
+    // Cursor of the window while it is not focused. The focused window's cursor lives in its buffer
+    pub cursor: (usize, usize),
+    pub rowoff: usize,
+    // Visual line of the row at `rowoff` shown at the top of the window in soft-wrap mode
+    pub wrapoff: usize,
+    pub coloff: usize,
+    pub rx: usize,
+    // Screen line of the cursor relative to the top of the window
+    pub cursor_row: usize,
+    pub rect: Rect,
+    // Vertical separator drawn at the right edge of the window
+    pub separator: bool,
+    pub gutter_width: usize,
+    // Cursor line which the gutter was last drawn for
+    pub gutter_cy: usize,
+    pub status_bar: StatusBar,
+    pub dirty_start: Option<usize>,
+}
+
+impl Window {
+    pub fn new(buf_idx: usize, status_bar: StatusBar) -> Self {
+        Self {
+            buf_idx,
+            cursor: (0, 0),
+            rowoff: 0,
+            wrapoff: 0,
+            coloff: 0,
+            rx: 0,
+            cursor_row: 0,
+            rect: Rect::default(),
+            separator: false,
+            gutter_width: 0,
+            gutter_cy: 0,
+            status_bar,
+            dirty_start: Some(0),
+        }
+    }
+
+    pub fn text_rows(&self) -> usize {
+        self.rect.height.saturating_sub(1)
+    }
+
+    pub fn text_cols(&self) -> usize {
+        let width = self.rect.width.saturating_sub(self.gutter_width);
+        if self.separator {
+            width.saturating_sub(1)
+        } else {
+            width
+        }
+    }
+
+    // Screen column where text starts, after the gutter
+    pub fn text_left(&self) -> usize {
+        self.rect.left + self.gutter_width
+    }
+
+    pub fn update_gutter(&mut self, gutter: &Gutter, num_lines: usize, cy: usize) {
+        let width = gutter.width(num_lines);
+        if width != self.gutter_width {
+            self.gutter_width = width;
+            self.redraw_all();
+        } else if gutter.line_numbers.depends_on_cursor() && cy != self.gutter_cy {
+            self.set_dirty_start(self.rowoff);
+        }
+        self.gutter_cy = cy;
+    }
+
+    pub fn set_dirty_start(&mut self, start: usize) {
+        if let Some(s) = self.dirty_start {
+            if s < start {
+                return;
+            }
+        }
+        self.dirty_start = Some(start);
+    }
+
+    pub fn redraw_all(&mut self) {
+        self.dirty_start = Some(self.rowoff);
+        self.status_bar.redraw = true;
+    }
+
+    fn next_coloff(&self, want_stop: usize, row: &Row) -> usize {
+        let mut coloff = 0;
+        for c in row.render_text().chars() {
+            coloff += c.width_cjk().unwrap_or(1);
+            if coloff >= want_stop {
+                break;
+            }
+        }
+        coloff
+    }
+
+    // Keeps the cursor in the window with `scroll_off` lines between the cursor and the top and
+    // the bottom edges. The margin shrinks in a small window and at the end of the buffer.
+    pub fn do_scroll(&mut self, rows: &[Row], cursor: (usize, usize), wrap: bool, scroll_off: usize) {
+        if wrap {
+            self.do_scroll_wrapped(rows, cursor, scroll_off);
+        } else {
+            self.do_scroll_unwrapped(rows, cursor, scroll_off);
+        }
+    }
+
+    fn margin(&self, scroll_off: usize) -> usize {
+        cmp::min(scroll_off, self.text_rows().saturating_sub(1) / 2)
+    }
+
+    fn do_scroll_unwrapped(&mut self, rows: &[Row], (cx, cy): (usize, usize), scroll_off: usize) {
+        let prev_rowoff = self.rowoff;
+        let prev_coloff = self.coloff;
+        let prev_wrapoff = self.wrapoff;
+        let num_rows = cmp::max(self.text_rows(), 1);
+        let num_cols = cmp::max(self.text_cols(), 1);
+        let margin = self.margin(scroll_off);
+        self.wrapoff = 0;
+
+        if cy < rows.len() {
+            self.rx = rows[cy].rx_from_cx(cmp::min(cx, rows[cy].len()));
+        } else {
+            self.rx = 0;
+        }
+
+        if cy < self.rowoff + margin {
+            self.rowoff = cy.saturating_sub(margin);
+        }
+        // Lines after the end of the buffer are not needed for the margin
+        let bottom = cmp::max(cmp::min(cy + margin, rows.len().saturating_sub(1)), cy);
+        if bottom >= self.rowoff + num_rows {
+            self.rowoff = bottom - num_rows + 1;
+        }
+        if self.rx < self.coloff {
+            self.coloff = self.rx;
+        }
+        if self.rx >= self.coloff + num_cols {
+            self.coloff = self.next_coloff(self.rx - num_cols + 1, &rows[cy]);
+        }
+        self.cursor_row = cy - self.rowoff;
+
+        if prev_rowoff != self.rowoff || prev_coloff != self.coloff || prev_wrapoff != 0 {
+            self.set_dirty_start(self.rowoff);
+        }
+    }
+
+    pub fn segments_of(&self, row: Option<&Row>) -> Vec<(usize, usize)> {
+        match row {
+            Some(row) => row.wrap_segments(cmp::max(self.text_cols(), 1)),
+            None => vec![(0, 0)],
+        }
+    }
+
+    // Number of visual lines of the row. Without soft-wrap every row is one visual line.
+    fn num_visual_lines(&self, rows: &[Row], y: usize, wrap: bool) -> usize {
+        if wrap {
+            self.segments_of(rows.get(y)).len()
+        } else {
+            1
+        }
+    }
+
+    // Visual lines are pointed by a row and the index of the wrapped segment in the row
+    fn prev_visual_line(&self, rows: &[Row], (y, k): (usize, usize), wrap: bool) -> Option<(usize, usize)> {
+        if k > 0 {
+            Some((y, k - 1))
+        } else if y > 0 {
+            Some((y - 1, self.num_visual_lines(rows, y - 1, wrap) - 1))
+        } else {
+            None
+        }
+    }
+
+    fn next_visual_line(&self, rows: &[Row], (y, k): (usize, usize), wrap: bool) -> Option<(usize, usize)> {
+        if k + 1 < self.num_visual_lines(rows, y, wrap) {
+            Some((y, k + 1))
+        } else if y + 1 < rows.len() {
+            Some((y + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    // Visual line of the cursor
+    fn cursor_line(&self, rows: &[Row], (cx, cy): (usize, usize), wrap: bool) -> (usize, usize) {
+        if !wrap {
+            return (cy, 0);
+        }
+        let segments = self.segments_of(rows.get(cy));
+        let idx = segments
+            .iter()
+            .position(|&(_, e)| cx < e)
+            .unwrap_or(segments.len() - 1);
+        (cy, idx)
+    }
+
+    // Walks up to `n` visual lines up or down and returns the line reached with the number of
+    // lines walked
+    fn walk(&self, rows: &[Row], mut line: (usize, usize), n: usize, up: bool, wrap: bool) -> ((usize, usize), usize) {
+        for walked in 0..n {
+            let next = if up {
+                self.prev_visual_line(rows, line, wrap)
+            } else {
+                self.next_visual_line(rows, line, wrap)
+            };
+            match next {
+                Some(next) => line = next,
+                None => return (line, walked),
+            }
+        }
+        (line, n)
+    }
+
+    fn set_top(&mut self, (y, k): (usize, usize)) {
+        if (self.rowoff, self.wrapoff) != (y, k) {
+            self.rowoff = y;
+            self.wrapoff = k;
+            self.set_dirty_start(y);
+        }
+    }
+
+    // Scrolls by visual lines. The top of the window is the visual line `wrapoff` of row `rowoff`.
+    fn do_scroll_wrapped(&mut self, rows: &[Row], (cx, cy): (usize, usize), scroll_off: usize) {
+        let prev = (self.rowoff, self.wrapoff, self.coloff);
+        let num_rows = cmp::max(self.text_rows(), 1);
+        let margin = self.margin(scroll_off);
+        self.coloff = 0;
+
+        let (_, idx) = self.cursor_line(rows, (cx, cy), true);
+        let segments = self.segments_of(rows.get(cy));
+        self.rx = match rows.get(cy) {
+            Some(row) => {
+                let cx = cmp::min(cx, row.len());
+                row.rx_from_cx(cx) - row.rx_from_cx(segments[idx].0)
+            }
+            None => 0,
+        };
+
+        if self.rowoff < rows.len() {
+            let len = self.segments_of(rows.get(self.rowoff)).len();
+            self.wrapoff = cmp::min(self.wrapoff, len - 1);
+        } else {
+            self.wrapoff = 0;
+        }
+
+        let (above, _) = self.walk(rows, (cy, idx), margin, true, true);
+        if above < (self.rowoff, self.wrapoff) {
+            self.rowoff = above.0;
+            self.wrapoff = above.1;
+        }
+        // Lines after the end of the buffer are not needed for the margin
+        let (_, below) = if cy < rows.len() {
+            self.walk(rows, (cy, idx), margin, false, true)
+        } else {
+            ((cy, idx), 0)
+        };
+
+        // Count visual lines from the top of the window to the cursor, giving up once it is
+        // known that the cursor is too low in the window
+        let mut lines = 0;
+        let mut y = self.rowoff;
+        let mut k = self.wrapoff;
+        while (y, k) < (cy, idx) && lines + below < num_rows {
+            lines += 1;
+            if k + 1 < self.segments_of(rows.get(y)).len() {
+                k += 1;
+            } else {
+                y += 1;
+                k = 0;
+            }
+        }
+
+        if lines + below >= num_rows {
+            // Walk back from the cursor so that the margin below the cursor is at the bottom
+            let (top, walked) = self.walk(rows, (cy, idx), num_rows - 1 - below, true, true);
+            self.rowoff = top.0;
+            self.wrapoff = top.1;
+            lines = walked;
+        }
+        self.cursor_row = lines;
+
+        if prev != (self.rowoff, self.wrapoff, self.coloff) {
+            self.set_dirty_start(self.rowoff);
+        }
+    }
+
+    // Scrolls the view by `delta` visual lines (positive is down) without moving the cursor. The
+    // last line of the buffer can be scrolled up to the top. Returns how many visual lines the
+    // cursor needs to move down (or up when negative) to stay in the window.
+    pub fn scroll_by(&mut self, rows: &[Row], delta: isize, wrap: bool, scroll_off: usize) -> isize {
+        let top = (self.rowoff, self.wrapoff);
+        let (top, walked) = self.walk(rows, top, delta.unsigned_abs(), delta < 0, wrap);
+        self.set_top(top);
+
+        let walked = if delta < 0 { -(walked as isize) } else { walked as isize };
+        let row = self.cursor_row as isize - walked;
+        let margin = self.margin(scroll_off) as isize;
+        let last = cmp::max(self.text_rows(), 1) as isize - 1;
+        let fixed = row.clamp(cmp::min(margin, last), cmp::max(last - margin, 0));
+        self.cursor_row = fixed.clamp(0, last) as usize;
+        fixed - row
+    }
+
+    // Scrolls so that the cursor is on the screen line `at` of the window
+    pub fn scroll_cursor_to(&mut self, rows: &[Row], cursor: (usize, usize), at: usize, wrap: bool) {
+        let line = self.cursor_line(rows, cursor, wrap);
+        let (top, walked) = self.walk(rows, line, at, true, wrap);
+        self.set_top(top);
+        self.cursor_row = walked;
+    }
+}
+
+enum Node {
+    Leaf(usize),
+    // Each child is paired with its length along the split direction
+    Split(SplitDir, Vec<(Node, usize)>),
+}
+
+impl Node {
+    fn min_len(&self, dir: SplitDir) -> usize {
+        match self {
+            // One text line and a status line, or one text column and a separator
+            Node::Leaf(_) => 2,
+            Node::Split(d, children) if *d == dir => {
+                children.iter().map(|(c, _)| c.min_len(dir)).sum()
+            }
+            Node::Split(_, children) => {
+                children.iter().map(|(c, _)| c.min_len(dir)).max().unwrap_or(2)
+            }
+        }
+    }
+
+    fn contains(&self, id: usize) -> bool {
+        match self {
+            Node::Leaf(i) => *i == id,
+            Node::Split(_, children) => children.iter().any(|(c, _)| c.contains(id)),
+        }
+    }
+
+    fn leaves(&self, out: &mut Vec<usize>) {
+        match self {
+            Node::Leaf(i) => out.push(*i),
+            Node::Split(_, children) => {
+                for (c, _) in children.iter() {
+                    c.leaves(out);
+                }
+            }
+        }
+    }
+
+    fn renumber_after_removal(&mut self, removed: usize) {
+        match self {
+            Node::Leaf(i) if *i > removed => *i -= 1,
+            Node::Leaf(_) => {}
+            Node::Split(_, children) => {
+                for (c, _) in children.iter_mut() {
+                    c.renumber_after_removal(removed);
+                }
+            }
+        }
+    }
+
+    fn split(&mut self, target: usize, new: usize, dir: SplitDir, len: usize) -> bool {
+        match self {
+            Node::Leaf(i) if *i == target => {
+                let first = len.div_ceil(2);
+                let leaves = vec![(Node::Leaf(target), first), (Node::Leaf(new), len - first)];
+                *self = Node::Split(dir, leaves);
+                true
+            }
+            Node::Leaf(_) => false,
+            Node::Split(d, children) => {
+                let d = *d;
+                let idx = match children.iter().position(|(c, _)| c.contains(target)) {
+                    Some(idx) => idx,
+                    None => return false,
+                };
+                if d == dir && matches!(children[idx].0, Node::Leaf(_)) {
+                    let size = children[idx].1;
+                    let first = size.div_ceil(2);
+                    children[idx].1 = first;
+                    children.insert(idx + 1, (Node::Leaf(new), size - first));
+                    return true;
+                }
+                children[idx].0.split(target, new, dir, len)
+            }
+        }
+    }
+
+    fn remove(&mut self, target: usize) -> bool {
+        let children = match self {
+            Node::Leaf(_) => return false,
+            Node::Split(_, children) => children,
+        };
+
+        let idx = match children.iter().position(|(c, _)| c.contains(target)) {
+            Some(idx) => idx,
+            None => return false,
+        };
+
+        if let Node::Leaf(_) = children[idx].0 {
+            let (_, size) = children.remove(idx);
+            // Give the freed space to the previous window, or the next one when it was the first
+            let neighbor = idx.saturating_sub(1);
+            children[neighbor].1 += size;
+            if children.len() == 1 {
+                let (only, _) = children.pop().unwrap();
+                *self = only;
+            }
+            true
+        } else {
+            children[idx].0.remove(target)
+        }
+    }
+
+    fn resize(&mut self, target: usize, delta: isize) -> bool {
+        let (d, children) = match self {
+            Node::Leaf(_) => return false,
+            Node::Split(d, children) => (*d, children),
+        };
+
+        let idx = match children.iter().position(|(c, _)| c.contains(target)) {
+            Some(idx) => idx,
+            None => return false,
+        };
+
+        if children[idx].0.resize(target, delta) {
+            return true;
+        }
+
+        // Trade space with the next sibling, or the previous one for the last child
+        let other = if idx + 1 < children.len() { idx + 1 } else { idx - 1 };
+        let (grow, shrink) = if delta > 0 { (idx, other) } else { (other, idx) };
+        let amount = delta.unsigned_abs();
+        let min = children[shrink].0.min_len(d);
+        if children[shrink].1 < min + amount {
+            return false;
+        }
+        children[shrink].1 -= amount;
+        children[grow].1 += amount;
+        true
+    }
+
+    fn arrange(&mut self, area: Rect, separator: bool, out: &mut Vec<(usize, Rect, bool)>) {
+        let (dir, children) = match self {
+            Node::Leaf(i) => {
+                out.push((*i, area, separator));
+                return;
+            }
+            Node::Split(dir, children) => (*dir, children),
+        };
+
+        let total = area.len(dir);
+        let sum: usize = children.iter().map(|(_, s)| *s).sum();
+        if sum != total {
+            // Divide the new space between children proportionally to their previous sizes
+            let mut rest = total;
+            let last = children.len() - 1;
+            for (i, (_, size)) in children.iter_mut().enumerate() {
+                *size = if i == last {
+                    rest
+                } else {
+                    let share = (*size * total).checked_div(sum).unwrap_or(total / (last + 1));
+                    cmp::min(rest, share)
+                };
+                rest -= *size;
+            }
+        }
+
+        let mut offset = 0;
+        let last = children.len() - 1;
+        for (i, (child, size)) in children.iter_mut().enumerate() {
+            let rect = match dir {
+                SplitDir::Horizontal => Rect {
+                    top: area.top + offset,
+                    height: *size,
+                    ..area
+                },
+                SplitDir::Vertical => Rect {
+                    left: area.left + offset,
+                    width: *size,
+                    ..area
+                },
+            };
+            let sep = match dir {
+                SplitDir::Horizontal => separator,
+                SplitDir::Vertical => i != last || separator,
+            };
+            child.arrange(rect, sep, out);
+            offset += *size;
+        }
+    }
+}
+
+pub struct Layout {
+    root: Node,
+}
+
+impl Layout {
+    pub fn new(id: usize) -> Self {
+        Self { root: Node::Leaf(id) }
+    }
+
+    pub fn split(&mut self, target: usize, new: usize, dir: SplitDir, target_rect: Rect) -> bool {
+        let len = target_rect.len(dir);
+        if len < 4 {
+            return false;
+        }
+        self.root.split(target, new, dir, len)
+    }
+
+    // Removes the window and shifts IDs of the windows after it so that they keep matching indices
+    pub fn remove(&mut self, target: usize) -> bool {
+        if !self.root.remove(target) {
+            return false;
+        }
+        self.root.renumber_after_removal(target);
+        true
+    }
+
+    // Grows or shrinks the window within the innermost split containing it
+    pub fn resize(&mut self, target: usize, delta: isize) -> bool {
+        self.root.resize(target, delta)
+    }
+
+    pub fn windows(&self) -> Vec<usize> {
+        let mut out = vec![];
+        self.root.leaves(&mut out);
+        out
+    }
+
+    pub fn arrange(&mut self, area: Rect) -> Vec<(usize, Rect, bool)> {
+        let mut out = vec![];
+        self.root.arrange(area, false, &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(top: usize, left: usize, width: usize, height: usize) -> Rect {
+        Rect {
+            top,
+            left,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn arrange_single_window() {
+        let mut layout = Layout::new(0);
+        assert_eq!(layout.arrange(rect(0, 0, 80, 24)), vec![(0, rect(0, 0, 80, 24), false)]);
+    }
+
+    #[test]
+    fn arrange_horizontal_split() {
+        let mut layout = Layout::new(0);
+        assert!(layout.split(0, 1, SplitDir::Horizontal, rect(0, 0, 80, 23)));
+        assert_eq!(
+            layout.arrange(rect(0, 0, 80, 23)),
+            vec![(0, rect(0, 0, 80, 12), false), (1, rect(12, 0, 80, 11), false)],
+        );
+    }
+
+    #[test]
+    fn arrange_vertical_split_with_separators() {
+        let mut layout = Layout::new(0);
+        assert!(layout.split(0, 1, SplitDir::Vertical, rect(0, 0, 80, 24)));
+        assert!(layout.split(1, 2, SplitDir::Horizontal, rect(0, 40, 40, 24)));
+        assert_eq!(
+            layout.arrange(rect(0, 0, 80, 24)),
+            vec![
+                (0, rect(0, 0, 40, 24), true),
+                (1, rect(0, 40, 40, 12), false),
+                (2, rect(12, 40, 40, 12), false),
+            ],
+        );
+        assert_eq!(layout.windows(), vec![0, 1, 2]);
+    }
+
+    fn heights(layout: &mut Layout, height: usize) -> Vec<usize> {
+        layout.arrange(rect(0, 0, 80, height)).iter().map(|(_, r, _)| r.height).collect()
+    }
+
+    #[test]
+    fn arrange_divides_new_space_proportionally() {
+        let mut layout = Layout::new(0);
+        assert!(layout.split(0, 1, SplitDir::Horizontal, rect(0, 0, 80, 20)));
+        assert!(layout.resize(0, 5));
+        assert_eq!(heights(&mut layout, 20), vec![15, 5]);
+        assert_eq!(heights(&mut layout, 40), vec![30, 10]);
+        assert_eq!(heights(&mut layout, 7), vec![5, 2]);
+    }
+
+    #[test]
+    fn resize_keeps_minimum_size() {
+        let mut layout = Layout::new(0);
+        assert!(layout.split(0, 1, SplitDir::Horizontal, rect(0, 0, 80, 10)));
+        layout.arrange(rect(0, 0, 80, 10));
+        assert!(layout.resize(0, 3));
+        assert!(!layout.resize(0, 1));
+        assert!(layout.resize(1, 1));
+    }
+
+    #[test]
+    fn split_rejects_too_small_window() {
+        let mut layout = Layout::new(0);
+        assert!(!layout.split(0, 1, SplitDir::Vertical, rect(0, 0, 3, 24)));
+        assert_eq!(layout.windows(), vec![0]);
+    }
+
+    #[test]
+    fn remove_gives_space_to_neighbor_and_renumbers() {
+        let mut layout = Layout::new(0);
+        assert!(layout.split(0, 1, SplitDir::Horizontal, rect(0, 0, 80, 24)));
+        assert!(layout.split(1, 2, SplitDir::Horizontal, rect(0, 12, 80, 12)));
+        layout.arrange(rect(0, 0, 80, 24));
+        assert!(layout.remove(0));
+        assert_eq!(layout.windows(), vec![0, 1]);
+        assert_eq!(
+            layout.arrange(rect(0, 0, 80, 24)),
+            vec![(0, rect(0, 0, 80, 18), false), (1, rect(18, 0, 80, 6), false)],
+        );
+        assert!(layout.remove(1));
+        assert_eq!(layout.arrange(rect(0, 0, 80, 24)), vec![(0, rect(0, 0, 80, 24), false)]);
+        assert!(!layout.remove(5));
+    }
+}